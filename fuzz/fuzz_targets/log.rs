@@ -0,0 +1,60 @@
+//! Logs records built from arbitrary bytes, run with `cargo fuzz run log`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use log::{Level, Log, Record};
+use win_service_logger::{Builder, Field, NulPolicy, Oversize, RecordingSink, Sid};
+
+fuzz_target!(|data: &[u8]| {
+    let (config, rest) = data.split_first().unwrap_or((&0, &[]));
+    let text = String::from_utf8_lossy(rest);
+    let mut parts = text.splitn(4, '\u{1}');
+    let msg = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let key = parts.next().unwrap_or_default();
+    let value = parts.next().unwrap_or_default();
+
+    let _ = value.parse::<Sid>();
+    let _ = Sid::from_bytes(rest);
+
+    let sink = RecordingSink::new();
+    let logger = Builder::new()
+        .nul_policy(match config & 3 {
+            0 => NulPolicy::Escape,
+            1 => NulPolicy::Strip,
+            2 => NulPolicy::Replace('\0'),
+            _ => NulPolicy::Replace('?'),
+        })
+        .oversize(if config & 4 == 0 {
+            Oversize::Truncate
+        } else {
+            Oversize::Split
+        })
+        .fields(if config & 8 == 0 {
+            vec![Field::Message]
+        } else {
+            vec![
+                Field::Level,
+                Field::KeyValues,
+                Field::Key(key.to_owned()),
+                Field::Message,
+            ]
+        })
+        .sink(sink.clone())
+        .build();
+
+    let kvs = [(key, value)];
+    logger.log(
+        &Record::builder()
+            .args(format_args!("{}", msg))
+            .level(Level::Error)
+            .target(target)
+            .key_values(&kvs)
+            .build(),
+    );
+
+    for event in sink.events() {
+        assert!(event.strings.iter().all(|s| !s.contains('\0')));
+    }
+});
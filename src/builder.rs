@@ -0,0 +1,625 @@
+//! Runtime configuration of a [`Logger`]
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use log::{Level, LevelFilter, Record};
+
+use crate::fields::Field;
+use crate::filter::{self, Filter, InvalidDirectives};
+use crate::ids::EventIds;
+use crate::limit::Limiter;
+use crate::queue::Queue;
+use crate::stats::ErrorCallback;
+use crate::writer::Writer;
+use crate::{Event, EventSink, Formatter, Logger, NulPolicy, Overflow, Oversize, RateLimit, Sid};
+
+/// Configures and creates a [`Logger`]
+///
+/// Every setting is optional, so values read from a configuration file can be applied one at a
+/// time.
+///
+/// # Example
+///
+/// ```
+/// use log::LevelFilter;
+/// use win_service_logger::{Builder, RecordingSink};
+///
+/// let logger = Builder::new()
+///     .source_name(String::from("My Service"))
+///     .max_level(LevelFilter::Info)
+///     .module_level("my_service::db", LevelFilter::Warn)
+///     .format(|buf, record| {
+///         use std::fmt::Write;
+///         write!(buf, "[{}] {}", record.target(), record.args())
+///     })
+///     .sink(RecordingSink::new())
+///     .build();
+/// ```
+pub struct Builder {
+    source_name: String,
+    filter: Filter,
+    ids: EventIds,
+    fields: Option<Vec<Field>>,
+    raw_data_limit: usize,
+    oversize: Oversize,
+    nul_policy: NulPolicy,
+    user_sid: Option<Sid>,
+    format: Option<Box<dyn Formatter>>,
+    machine: Option<String>,
+    sink: Option<Box<dyn EventSink>>,
+    fallback: Option<Box<dyn EventSink>>,
+    tees: Vec<Box<dyn EventSink>>,
+    detect_console: bool,
+    async_queue: Option<(usize, Overflow)>,
+    rate_limit: Option<RateLimit>,
+    on_error: Option<ErrorCallback>,
+    #[cfg(feature = "registry")]
+    registry: Option<Arc<dyn crate::registry::KeyStore>>,
+    #[cfg(feature = "registry")]
+    watch_registry: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            source_name: String::from("Rust Application"),
+            filter: Filter::new(LevelFilter::Trace),
+            ids: EventIds::default(),
+            fields: None,
+            raw_data_limit: crate::DEFAULT_RAW_DATA_LIMIT,
+            oversize: Oversize::default(),
+            nul_policy: NulPolicy::default(),
+            user_sid: None,
+            format: None,
+            machine: None,
+            sink: None,
+            fallback: None,
+            tees: Vec::new(),
+            detect_console: false,
+            async_queue: None,
+            rate_limit: None,
+            on_error: None,
+            #[cfg(feature = "registry")]
+            registry: None,
+            #[cfg(feature = "registry")]
+            watch_registry: false,
+        }
+    }
+
+    /// Sets the event source name events are reported under
+    ///
+    /// Defaults to `"Rust Application"`
+    pub fn source_name(mut self, name: impl Into<String>) -> Self {
+        self.source_name = name.into();
+        self
+    }
+
+    /// Sets the most verbose level that is written for modules without their own level
+    ///
+    /// Defaults to [`LevelFilter::Trace`]. It can be changed later with [`Logger::set_max_level`]
+    pub fn max_level(self, level: LevelFilter) -> Self {
+        self.filter.set_level(level);
+        self
+    }
+
+    /// Sets the most verbose level that is written for `module` and its submodules
+    ///
+    /// The longest matching module path takes precedence.
+    pub fn module_level(self, module: impl Into<String>, level: LevelFilter) -> Self {
+        self.filter.insert(module.into(), level);
+        self
+    }
+
+    /// Applies `env_logger` style filter directives such as `info,my_service=trace,hyper=warn`
+    ///
+    /// A bare level sets the default level and `module=level` sets the level for a module and
+    /// its submodules, matched against the target of each record. Directives are applied in
+    /// order, on top of any levels already set. Invalid directives are ignored, use
+    /// [`Builder::try_parse_filters`] to find out about them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{Builder, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .parse_filters("info,my_service=trace,hyper=warn")
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// let debug = |target| {
+    ///     log::Record::builder()
+    ///         .args(format_args!("details"))
+    ///         .level(log::Level::Debug)
+    ///         .target(target)
+    ///         .build()
+    /// };
+    /// logger.log(&debug("hyper::proto"));
+    /// logger.log(&debug("tokio"));
+    /// logger.log(&debug("my_service::db"));
+    ///
+    /// assert_eq!(sink.events().len(), 1);
+    /// ```
+    pub fn parse_filters(self, filters: &str) -> Self {
+        // Invalid directives are skipped on purpose, see `try_parse_filters`
+        let _ = self.filter.parse(filters);
+        self
+    }
+
+    /// Applies filter directives like [`Builder::parse_filters`], unless one of them is invalid
+    ///
+    /// # Errors
+    ///
+    /// Returns the invalid directives, in which case none of the directives are applied
+    pub fn try_parse_filters(self, filters: &str) -> Result<Self, InvalidDirectives> {
+        for directive in filter::parse_directives(filters)? {
+            self.filter.apply(directive);
+        }
+        Ok(self)
+    }
+
+    /// Applies filter directives read from the environment variable `var`, if it is set
+    ///
+    /// See [`Builder::parse_filters`] for the syntax. `env_logger` reads `RUST_LOG`.
+    pub fn parse_env(self, var: &str) -> Self {
+        match std::env::var(var) {
+            Ok(filters) => self.parse_filters(&filters),
+            Err(_) => self,
+        }
+    }
+
+    /// Applies filter directives read from the environment variable `var` like
+    /// [`Builder::try_parse_filters`], if it is set
+    ///
+    /// # Errors
+    ///
+    /// Returns the invalid directives, in which case none of the directives are applied
+    pub fn try_parse_env(self, var: &str) -> Result<Self, InvalidDirectives> {
+        match std::env::var(var) {
+            Ok(filters) => self.try_parse_filters(&filters),
+            Err(_) => Ok(self),
+        }
+    }
+
+    /// Sets the event ID used for records at `level`
+    ///
+    /// Records can override it with an [`event_id`](crate::EVENT_ID_KEY) key-value. Defaults
+    /// to 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::{Level, Log};
+    /// use win_service_logger::{Builder, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .event_id(Level::Error, 1000)
+    ///     .category("my_service::db", 2)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// let kvs = [("event_id", 1001)];
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("disk full"))
+    ///         .level(Level::Error)
+    ///         .target("my_service::db::pool")
+    ///         .key_values(&kvs)
+    ///         .build(),
+    /// );
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("connection lost"))
+    ///         .level(Level::Error)
+    ///         .build(),
+    /// );
+    ///
+    /// let events = sink.events();
+    /// assert_eq!((events[0].event_id, events[0].category), (1001, 2));
+    /// assert_eq!((events[1].event_id, events[1].category), (1000, 0));
+    /// ```
+    pub fn event_id(mut self, level: Level, event_id: u32) -> Self {
+        self.ids.set_level(level, event_id);
+        self
+    }
+
+    /// Sets the category used for records whose target is `target` or one of its submodules
+    ///
+    /// Records can override it with a [`category`](crate::CATEGORY_KEY) key-value. The longest
+    /// matching target takes precedence, and the default is 0.
+    pub fn category(mut self, target: impl Into<String>, category: u16) -> Self {
+        self.ids.set_category(target.into(), category);
+        self
+    }
+
+    /// Sets the insertion strings of every event, in order
+    ///
+    /// Defaults to only [`Field::Message`]. A message table template refers to the strings as
+    /// `%1`, `%2`, ...
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{format, Builder, Field, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .fields([Field::Message, Field::Key(String::from("user")), Field::Line])
+    ///     .formatter(format::Message)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// let kvs = [("user", "alice")];
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("logged in"))
+    ///         .level(log::Level::Info)
+    ///         .line(Some(42))
+    ///         .key_values(&kvs)
+    ///         .build(),
+    /// );
+    ///
+    /// assert_eq!(sink.events()[0].strings, ["logged in", "alice", "42"]);
+    /// ```
+    pub fn fields(mut self, fields: impl IntoIterator<Item = Field>) -> Self {
+        self.fields = Some(fields.into_iter().collect());
+        self
+    }
+
+    /// Sets the maximum number of bytes of [binary data](crate::RawData) attached to an event
+    ///
+    /// Longer data is truncated. Defaults to
+    /// [`DEFAULT_RAW_DATA_LIMIT`](crate::DEFAULT_RAW_DATA_LIMIT).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{Builder, RawData, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .raw_data_limit(2)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// let context = [0xde, 0xad, 0xbe, 0xef];
+    /// let kvs = [("raw_data", RawData(&context))];
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("request failed"))
+    ///         .level(log::Level::Error)
+    ///         .key_values(&kvs)
+    ///         .build(),
+    /// );
+    ///
+    /// assert_eq!(sink.events()[0].raw_data, [0xde, 0xad]);
+    /// ```
+    pub fn raw_data_limit(mut self, bytes: usize) -> Self {
+        self.raw_data_limit = bytes;
+        self
+    }
+
+    /// Sets what happens to events which are too large for the event log
+    ///
+    /// By default their longest strings are [truncated](Oversize::Truncate).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{format, Builder, Oversize, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .formatter(format::Message)
+    ///     .oversize(Oversize::Split)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// let dump = "x".repeat(70_000);
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("{}", dump))
+    ///         .level(log::Level::Error)
+    ///         .build(),
+    /// );
+    ///
+    /// let events = sink.events();
+    /// assert_eq!(events.len(), 3);
+    /// assert!(events[0].strings[0].starts_with("[part 1/3 id="));
+    /// assert!(events[2].strings[0].starts_with("[part 3/3 id="));
+    /// let total: usize = events.iter().map(|e| e.strings[0].matches('x').count()).sum();
+    /// assert_eq!(total, 70_000);
+    /// ```
+    pub fn oversize(mut self, policy: Oversize) -> Self {
+        self.oversize = policy;
+        self
+    }
+
+    /// Sets what is written in place of NUL characters in insertion strings
+    ///
+    /// By default they are [escaped](NulPolicy::Escape) as `\0`.
+    pub fn nul_policy(mut self, policy: NulPolicy) -> Self {
+        self.nul_policy = policy;
+        self
+    }
+
+    /// Attaches `sid` to every event that doesn't get a SID from its record or a
+    /// [`UserSidScope`](crate::UserSidScope)
+    ///
+    /// Use [`Sid::current_user`] to attach the user the service runs as. By default events
+    /// have no user.
+    pub fn user_sid(mut self, sid: Sid) -> Self {
+        self.user_sid = Some(sid);
+        self
+    }
+
+    /// Sets the function that turns a record into the message shown in Event Viewer
+    ///
+    /// The default format is `"{file}({line}): {level} - {message}"`. See
+    /// [`Builder::formatter`] for the built-in presets.
+    pub fn format<F>(self, format: F) -> Self
+    where
+        F: Fn(&mut String, &Record) -> fmt::Result + Send + Sync + 'static,
+    {
+        self.formatter(format)
+    }
+
+    /// Sets the [`Formatter`] that turns a record into the message shown in Event Viewer
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{format, Builder, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .formatter(format::ModulePath)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// logger.log(
+    ///     &log::Record::builder()
+    ///         .args(format_args!("disk full"))
+    ///         .level(log::Level::Error)
+    ///         .module_path(Some("my_service::db"))
+    ///         .build(),
+    /// );
+    ///
+    /// assert_eq!(sink.events()[0].strings, ["my_service::db: disk full"]);
+    /// ```
+    pub fn formatter(mut self, formatter: impl Formatter + 'static) -> Self {
+        self.format = Some(Box::new(formatter));
+        self
+    }
+
+    /// Writes to the event log of the remote computer `machine` instead of the local one
+    ///
+    /// Ignored if a custom sink is set with [`Builder::sink`]
+    pub fn machine(mut self, machine: impl Into<String>) -> Self {
+        self.machine = Some(machine.into());
+        self
+    }
+
+    /// Writes events to `sink` instead of the Windows Event Log
+    pub fn sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Writes events the sink fails to write to `fallback` instead, such as [`StderrSink`] or a
+    /// [`FileSink`]
+    ///
+    /// By default such events are dropped. See [`Stats`](crate::Stats) for an example.
+    ///
+    /// [`StderrSink`]: crate::StderrSink
+    /// [`FileSink`]: crate::FileSink
+    pub fn fallback(mut self, fallback: impl EventSink + 'static) -> Self {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Also writes every event to `tee`, next to the main sink
+    ///
+    /// Can be called more than once. Errors from tees are ignored, they don't count as failures
+    /// in [`Logger::stats`].
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use win_service_logger::{Builder, RollingFileSink, StderrSink};
+    ///
+    /// Builder::new()
+    ///     .source_name("My Service")
+    ///     .tee(StderrSink)
+    ///     .tee(RollingFileSink::open("my-service.log", 10 * 1024 * 1024, 5).unwrap())
+    ///     .init();
+    /// ```
+    pub fn tee(mut self, tee: impl EventSink + 'static) -> Self {
+        self.tees.push(Box::new(tee));
+        self
+    }
+
+    /// Also writes every event to stderr if the program runs in a console, see
+    /// [`has_console`](crate::has_console)
+    ///
+    /// A service started by the service control manager has no console, so its events only go
+    /// to the event log, while the same binary started from a terminal also prints them, even
+    /// with stderr redirected to a file. Nothing is added if the main sink already is the stderr
+    /// fallback used outside of Windows.
+    pub fn detect_console(mut self) -> Self {
+        self.detect_console = true;
+        self
+    }
+
+    /// Writes events on a background thread, through a queue of at most `capacity` events
+    ///
+    /// Logging then only builds the event and queues it. `overflow` decides what happens when
+    /// the queue is full, and [`Log::flush`](log::Log::flush) waits until it is empty. Events
+    /// still queued when the logger is dropped are written first. If the thread can't be
+    /// started events are written on the logging thread as usual.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{Builder, Overflow, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Builder::new()
+    ///     .async_queue(1024, Overflow::DropOldest)
+    ///     .sink(sink.clone())
+    ///     .build();
+    ///
+    /// for i in 0..100 {
+    ///     logger.log(
+    ///         &log::Record::builder()
+    ///             .args(format_args!("request {} done", i))
+    ///             .level(log::Level::Info)
+    ///             .build(),
+    ///     );
+    /// }
+    /// logger.flush();
+    ///
+    /// assert_eq!(sink.events().len(), 100);
+    /// assert_eq!(logger.stats().dropped, 0);
+    /// ```
+    pub fn async_queue(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.async_queue = Some((capacity, overflow));
+        self
+    }
+
+    /// Limits how often records with the same call site or message are written
+    ///
+    /// Records over the limit are counted in [`Stats::suppressed`](crate::Stats::suppressed)
+    /// and summarized in a later event. See [`RateLimit`] for an example.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Calls `callback` with the error and the event every time the sink fails to write an event
+    ///
+    /// The callback runs on the logging thread, before the event is written to the
+    /// [fallback](Builder::fallback). It must not log through this logger, since a sink that
+    /// keeps failing would call it again.
+    pub fn on_error<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&io::Error, &Event) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+
+    /// Reads the level and filter directives from `store` when the logger is built, and again
+    /// on every call to [`Logger::reload`]
+    ///
+    /// Values in the store are applied on top of the levels set on this builder. See the
+    /// [`registry`](crate::registry) module for the value names.
+    #[cfg(feature = "registry")]
+    pub fn registry(mut self, store: impl crate::registry::KeyStore + 'static) -> Self {
+        self.registry = Some(Arc::new(store));
+        self
+    }
+
+    /// Reloads the configuration from the store given to [`Builder::registry`] whenever it
+    /// changes, using a background thread
+    #[cfg(feature = "registry")]
+    pub fn watch_registry(mut self) -> Self {
+        self.watch_registry = true;
+        self
+    }
+
+    /// Creates the configured logger
+    ///
+    /// Unless a sink was set with [`Builder::sink`] the logger writes to the Windows Event Log,
+    /// or to stderr on other platforms.
+    pub fn build(mut self) -> Logger {
+        if self.detect_console && (cfg!(windows) || self.sink.is_some()) && crate::has_console() {
+            self.tees.push(Box::new(crate::StderrSink));
+        }
+        let sink = match self.sink {
+            Some(sink) => sink,
+            None => default_sink(self.source_name, self.machine),
+        };
+        let mut writer = Writer::new(sink);
+        writer.tees = self.tees;
+        writer.fallback = self.fallback;
+        writer.on_error = self.on_error;
+        let mut logger = Logger::from_parts(writer, self.filter);
+        logger.ids = self.ids;
+        logger.raw_data_limit = self.raw_data_limit;
+        logger.oversize = self.oversize;
+        logger.nul_policy = self.nul_policy;
+        logger.user_sid = self.user_sid;
+        logger.limiter = self.rate_limit.map(Limiter::new);
+        if let Some((capacity, overflow)) = self.async_queue {
+            // Without the thread events are written on the logging thread instead
+            logger.queue = Queue::spawn(capacity, overflow, Arc::clone(&logger.writer)).ok();
+        }
+        if let Some(fields) = self.fields {
+            logger.fields = fields;
+        }
+        if let Some(format) = self.format {
+            logger.format = format;
+        }
+        #[cfg(feature = "registry")]
+        if let Some(store) = self.registry {
+            let config = crate::registry::RegistryConfig::new(store, &logger.filter);
+            // A missing, unreadable or invalid key leaves the levels from the builder in place
+            let _ = config.apply(&logger.filter);
+            if self.watch_registry {
+                let _ = config.clone().watch(Arc::downgrade(&logger.filter));
+            }
+            logger.registry = Some(config);
+        }
+        logger
+    }
+
+    /// Creates the configured logger and installs it as the global logger
+    ///
+    /// # Errors
+    ///
+    /// This function fails if a global logger has already been set
+    pub fn try_init(self) -> Result<(), log::SetLoggerError> {
+        self.build().try_init()
+    }
+
+    /// Creates the configured logger and installs it as the global logger
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a global logger has already been set
+    /// Use [`Builder::try_init`] for a fallable function
+    pub fn init(self) {
+        self.try_init().unwrap();
+    }
+}
+
+#[cfg(windows)]
+fn default_sink(source_name: String, machine: Option<String>) -> Box<dyn EventSink> {
+    let sink = crate::EventLogSink::new(source_name);
+    Box::new(match machine {
+        Some(machine) => sink.machine(machine),
+        None => sink,
+    })
+}
+
+#[cfg(not(windows))]
+fn default_sink(_source_name: String, _machine: Option<String>) -> Box<dyn EventSink> {
+    Box::new(crate::StderrSink)
+}
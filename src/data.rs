@@ -0,0 +1,104 @@
+//! Attaching binary data to events
+//!
+//! Event Viewer shows the raw data of an event under "Details > Binary data". A record
+//! attaches data through the [`RAW_DATA_KEY`] key-value. Values written by the [`RawData`]
+//! wrapper, hex prefixed with [`RAW_DATA_HEX_PREFIX`], are decoded back into bytes:
+//!
+//! ```
+//! use win_service_logger::RawData;
+//!
+//! let context = [0xde, 0xad, 0xbe, 0xef];
+//! log::error!(raw_data = RawData(&context); "request failed");
+//! ```
+//!
+//! Any other value, including text that happens to look like hex, is attached as its UTF-8
+//! text. Data longer than the limit set with
+//! [`Builder::raw_data_limit`](crate::Builder::raw_data_limit) is truncated.
+
+use std::fmt;
+
+use log::kv::{Key, ToValue, Value};
+use log::Record;
+
+/// Name of the key-value that holds the binary data of a record
+pub const RAW_DATA_KEY: &str = "raw_data";
+
+/// Marks a [`RAW_DATA_KEY`] value as hex to decode, such as `hex:deadbeef`
+pub const RAW_DATA_HEX_PREFIX: &str = "hex:";
+
+/// The default limit on the binary data of a single event
+///
+/// An event, including its insertion strings, can't be larger than about 61 KB.
+pub const DEFAULT_RAW_DATA_LIMIT: usize = 16 * 1024;
+
+/// Bytes to attach to an event through the [`RAW_DATA_KEY`] key-value
+///
+/// The bytes are formatted as lowercase hex after [`RAW_DATA_HEX_PREFIX`], which the logger
+/// decodes again.
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{Logger, RawData, RecordingSink};
+///
+/// let hex = RawData(&[0xca, 0xfe]).to_string();
+/// assert_eq!(hex, "hex:cafe");
+///
+/// let sink = RecordingSink::new();
+/// let logger = Logger::new(sink.clone());
+/// for value in [hex.as_str(), "cafe"] {
+///     let kvs = [("raw_data", value)];
+///     logger.log(
+///         &log::Record::builder()
+///             .args(format_args!("request failed"))
+///             .level(log::Level::Error)
+///             .key_values(&kvs)
+///             .build(),
+///     );
+/// }
+///
+/// let events = sink.events();
+/// assert_eq!(events[0].raw_data, [0xca, 0xfe]);
+/// assert_eq!(events[1].raw_data, b"cafe");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawData<'a>(pub &'a [u8]);
+
+impl fmt::Display for RawData<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(RAW_DATA_HEX_PREFIX)?;
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToValue for RawData<'_> {
+    fn to_value(&self) -> Value<'_> {
+        Value::from_display(self)
+    }
+}
+
+/// Returns the binary data attached to `record`, at most `limit` bytes of it
+pub(crate) fn raw_data(record: &Record, limit: usize) -> Vec<u8> {
+    let value = match record.key_values().get(Key::from_str(RAW_DATA_KEY)) {
+        Some(value) => value.to_string(),
+        None => return Vec::new(),
+    };
+    let decoded = value.strip_prefix(RAW_DATA_HEX_PREFIX).and_then(decode_hex);
+    let mut data = decoded.unwrap_or_else(|| value.into_bytes());
+    data.truncate(limit);
+    data
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,121 @@
+//! Writing `slog` records to the event log
+//!
+//! [`EventLogDrain`] is a [`slog::Drain`] which turns every `slog` record into a `log` record and
+//! hands it to a [`Logger`], so levels, formatting, insertion strings, event IDs and sinks are
+//! configured on the [`Builder`](crate::Builder) as usual.
+//!
+//! The key-values of the record and of the `slog` logger become key-values of the `log` record,
+//! those of the record first. They can be sent as insertion strings with
+//! [`Field::Key`](crate::Field::Key) and [`Field::KeyValues`](crate::Field::KeyValues). The
+//! module of the record is used as its target.
+
+use std::fmt;
+
+use log::Log;
+use slog::{Drain, Key, Never, OwnedKVList, Serializer, KV};
+
+use crate::{EventType, Logger};
+
+/// A [`Drain`] which writes `slog` records through a [`Logger`]
+///
+/// # Example
+///
+/// ```
+/// use win_service_logger::{format, Builder, EventLogDrain, EventType, Field, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Message)
+///     .fields([Field::Message, Field::KeyValues])
+///     .sink(sink.clone())
+///     .build();
+/// let root = slog::Logger::root(EventLogDrain::new(logger), slog::o!("component" => "db"));
+///
+/// slog::crit!(root, "connection lost"; "retries" => 3);
+///
+/// let events = sink.events();
+/// assert_eq!(events[0].event_type, EventType::Error);
+/// assert_eq!(events[0].strings, ["connection lost", "retries=3 component=db"]);
+/// ```
+pub struct EventLogDrain {
+    logger: Logger,
+}
+
+impl EventLogDrain {
+    /// Creates a drain which writes records through `logger`
+    pub fn new(logger: Logger) -> Self {
+        Self { logger }
+    }
+}
+
+// `slog::Logger` requires its drain to be unwind safe. A panic in the middle of logging can't
+// leave the `Logger` unusable, since it recovers the data of poisoned locks.
+impl std::panic::UnwindSafe for EventLogDrain {}
+impl std::panic::RefUnwindSafe for EventLogDrain {}
+
+impl Drain for EventLogDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &slog::Record, values: &OwnedKVList) -> Result<(), Never> {
+        let level = log_level(record.level());
+        if !self.logger.enabled(
+            &log::Metadata::builder()
+                .level(level)
+                .target(record.module())
+                .build(),
+        ) {
+            return Ok(());
+        }
+
+        let mut key_values = KeyValues(Vec::new());
+        // Collecting into a Vec can't fail
+        let _ = record.kv().serialize(record, &mut key_values);
+        let _ = values.serialize(record, &mut key_values);
+        let key_values: Vec<(&str, &str)> = key_values
+            .0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+
+        self.logger.log(
+            &log::Record::builder()
+                .args(*record.msg())
+                .level(level)
+                .target(record.module())
+                .module_path(Some(record.module()))
+                .file(Some(record.file()))
+                .line(Some(record.line()))
+                .key_values(&key_values.as_slice())
+                .build(),
+        );
+        Ok(())
+    }
+}
+
+impl From<slog::Level> for EventType {
+    fn from(level: slog::Level) -> Self {
+        log_level(level).into()
+    }
+}
+
+/// Maps `slog` levels onto `log` levels, with `Critical` becoming `Error`
+fn log_level(level: slog::Level) -> log::Level {
+    match level {
+        slog::Level::Critical | slog::Level::Error => log::Level::Error,
+        slog::Level::Warning => log::Level::Warn,
+        slog::Level::Info => log::Level::Info,
+        slog::Level::Debug => log::Level::Debug,
+        slog::Level::Trace => log::Level::Trace,
+    }
+}
+
+/// Collects key-values as strings
+struct KeyValues(Vec<(String, String)>);
+
+impl Serializer for KeyValues {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
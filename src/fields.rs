@@ -0,0 +1,81 @@
+//! Splitting a record into insertion strings
+//!
+//! By default an event has a single insertion string holding the formatted message. With
+//! [`Builder::fields`](crate::Builder::fields) parts of the record are sent as separate
+//! insertion strings instead, in the configured order, so a message table template can refer
+//! to them as `%1`, `%2`, ... and XML queries in Event Viewer can match on them.
+
+use std::fmt::Write;
+
+use log::kv::{Key, Value, VisitSource};
+use log::Record;
+
+use crate::data::RAW_DATA_KEY;
+use crate::ids::{CATEGORY_KEY, EVENT_ID_KEY};
+use crate::sid::USER_SID_KEY;
+
+/// A part of a record sent as its own insertion string
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Field {
+    /// The message, as written by the formatter
+    Message,
+    /// The level, such as `WARN`
+    Level,
+    /// The target
+    Target,
+    /// The module path, or an empty string if it isn't known
+    ModulePath,
+    /// The source file, or an empty string if it isn't known
+    File,
+    /// The line number, or an empty string if it isn't known
+    Line,
+    /// The value of a single key-value, or an empty string if the record doesn't have it
+    Key(String),
+    /// Every key-value as `key=value`, separated by spaces
+    ///
+    /// The [`event_id`](crate::EVENT_ID_KEY), [`category`](crate::CATEGORY_KEY),
+    /// [`raw_data`](crate::RAW_DATA_KEY) and [`user_sid`](crate::USER_SID_KEY) keys are left out
+    /// since they are already part of the event.
+    KeyValues,
+}
+
+/// Returns the insertion strings for `record`, one for each of `fields`
+pub(crate) fn insertion_strings(fields: &[Field], record: &Record, message: &str) -> Vec<String> {
+    fields
+        .iter()
+        .map(|field| match field {
+            Field::Message => message.to_owned(),
+            Field::Level => record.level().to_string(),
+            Field::Target => record.target().to_owned(),
+            Field::ModulePath => record.module_path().unwrap_or_default().to_owned(),
+            Field::File => record.file().unwrap_or_default().to_owned(),
+            Field::Line => record.line().map(|l| l.to_string()).unwrap_or_default(),
+            Field::Key(key) => record
+                .key_values()
+                .get(Key::from_str(key))
+                .map(|value| value.to_string())
+                .unwrap_or_default(),
+            Field::KeyValues => {
+                let mut visitor = KeyValues(String::new());
+                let _ = record.key_values().visit(&mut visitor);
+                visitor.0
+            }
+        })
+        .collect()
+}
+
+struct KeyValues(String);
+
+impl<'kvs> VisitSource<'kvs> for KeyValues {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        if [EVENT_ID_KEY, CATEGORY_KEY, RAW_DATA_KEY, USER_SID_KEY].contains(&key.as_str()) {
+            return Ok(());
+        }
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        // Writing to a String can't fail
+        let _ = write!(self.0, "{}={}", key, value);
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Level filtering by module path
+//!
+//! Filters can be written as `env_logger` style directives such as
+//! `info,my_service=trace,hyper=warn`: a bare level sets the default level, `module=level` sets
+//! the level for a module and its submodules, and a bare module name enables everything from it.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use log::{LevelFilter, Metadata};
+
+/// The directives in a filter which couldn't be parsed
+///
+/// # Example
+///
+/// ```
+/// use win_service_logger::Builder;
+///
+/// let err = Builder::new()
+///     .try_parse_filters("info,hyper=loud,=warn")
+///     .err()
+///     .unwrap();
+/// assert_eq!(err.directives(), ["hyper=loud", "=warn"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidDirectives {
+    directives: Vec<String>,
+}
+
+impl InvalidDirectives {
+    /// The rejected directives, in the order they were given
+    pub fn directives(&self) -> &[String] {
+        &self.directives
+    }
+}
+
+impl fmt::Display for InvalidDirectives {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid logging directives:")?;
+        for (i, directive) in self.directives.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}'{}'", separator, directive)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidDirectives {}
+
+impl From<InvalidDirectives> for io::Error {
+    fn from(err: InvalidDirectives) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A parsed directive, with no module for the default level
+pub(crate) type Directive = (Option<String>, LevelFilter);
+
+/// Decides which records are written, based on a default level and per-module overrides
+///
+/// Every part of a filter can be changed while the logger is in use
+#[derive(Debug)]
+pub(crate) struct Filter {
+    level: AtomicUsize,
+    /// Module path prefixes and their levels, sorted so that the longest prefix comes first
+    modules: RwLock<Vec<(String, LevelFilter)>>,
+    /// Set once the logger owning this filter is the global logger, after which every change
+    /// is mirrored into [`log::set_max_level`]
+    global: AtomicBool,
+}
+
+/// A copy of the levels in a [`Filter`] at some point in time
+#[cfg(feature = "registry")]
+#[derive(Debug, Clone)]
+pub(crate) struct FilterState {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub(crate) fn new(level: LevelFilter) -> Self {
+        Self {
+            level: AtomicUsize::new(level as usize),
+            modules: RwLock::new(Vec::new()),
+            global: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn level(&self) -> LevelFilter {
+        level_from_usize(self.level.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_level(&self, level: LevelFilter) {
+        self.level.store(level as usize, Ordering::Relaxed);
+        self.sync_global();
+    }
+
+    /// Sets the level for `module` and all of its submodules
+    pub(crate) fn insert(&self, module: String, level: LevelFilter) {
+        let mut modules = self.modules_mut();
+        match modules.iter_mut().find(|(name, _)| *name == module) {
+            Some(entry) => entry.1 = level,
+            None => {
+                modules.push((module, level));
+                modules.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+            }
+        }
+        drop(modules);
+        self.sync_global();
+    }
+
+    #[cfg(feature = "registry")]
+    pub(crate) fn state(&self) -> FilterState {
+        FilterState {
+            level: self.level(),
+            modules: self.modules().clone(),
+        }
+    }
+
+    /// Replaces every level with the ones in `state`
+    #[cfg(feature = "registry")]
+    pub(crate) fn restore(&self, state: &FilterState) {
+        *self.modules_mut() = state.modules.clone();
+        self.set_level(state.level);
+    }
+
+    /// Marks this filter as belonging to the global logger
+    pub(crate) fn set_global(&self) {
+        self.global.store(true, Ordering::Relaxed);
+        self.sync_global();
+    }
+
+    /// Applies the valid directives in a comma separated list
+    ///
+    /// Invalid directives are skipped, so one typo doesn't disable logging entirely, and
+    /// returned as the error
+    pub(crate) fn parse(&self, spec: &str) -> Result<(), InvalidDirectives> {
+        let (directives, invalid) = partition(spec);
+        for directive in directives {
+            self.apply(directive);
+        }
+        invalid.map_or(Ok(()), Err)
+    }
+
+    /// Applies a single parsed directive
+    pub(crate) fn apply(&self, (module, level): Directive) {
+        match module {
+            None => self.set_level(level),
+            Some(module) => self.insert(module, level),
+        }
+    }
+
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// The most verbose level any record could be written at
+    pub(crate) fn max_level(&self) -> LevelFilter {
+        self.modules()
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level(), std::cmp::max)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules()
+            .iter()
+            .find(|(name, _)| is_module_prefix(name, target))
+            .map_or_else(|| self.level(), |(_, level)| *level)
+    }
+
+    fn sync_global(&self) {
+        if self.global.load(Ordering::Relaxed) {
+            log::set_max_level(self.max_level());
+        }
+    }
+
+    fn modules(&self) -> RwLockReadGuard<'_, Vec<(String, LevelFilter)>> {
+        // Writers only replace or push whole entries, so the data is usable after a panic
+        self.modules.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn modules_mut(&self) -> RwLockWriteGuard<'_, Vec<(String, LevelFilter)>> {
+        self.modules.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Parses a comma separated list of directives, failing if any of them is invalid
+pub(crate) fn parse_directives(spec: &str) -> Result<Vec<Directive>, InvalidDirectives> {
+    match partition(spec) {
+        (directives, None) => Ok(directives),
+        (_, Some(invalid)) => Err(invalid),
+    }
+}
+
+/// Parses a comma separated list of directives into the valid and the invalid ones
+fn partition(spec: &str) -> (Vec<Directive>, Option<InvalidDirectives>) {
+    let mut directives = Vec::new();
+    let mut invalid = Vec::new();
+    for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        match parse_directive(directive) {
+            Some(parsed) => directives.push(parsed),
+            None => invalid.push(directive.to_owned()),
+        }
+    }
+    let invalid = (!invalid.is_empty()).then_some(InvalidDirectives {
+        directives: invalid,
+    });
+    (directives, invalid)
+}
+
+/// Splits a single directive into its module, if any, and level
+fn parse_directive(directive: &str) -> Option<Directive> {
+    let mut parts = directive.split('=').map(str::trim);
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(part), None, None) => match part.parse() {
+            Ok(level) => Some((None, level)),
+            Err(_) => Some((Some(part.to_owned()), LevelFilter::Trace)),
+        },
+        (Some(module), Some(level), None) if !module.is_empty() => level
+            .parse()
+            .ok()
+            .map(|level| (Some(module.to_owned()), level)),
+        _ => None,
+    }
+}
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Returns true if `target` is `module` or one of its submodules
+pub(crate) fn is_module_prefix(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
@@ -0,0 +1,107 @@
+//! Turning records into messages
+//!
+//! A [`Formatter`] writes the message of a record into a buffer which the logger reuses between
+//! records. Closures with the signature `Fn(&mut String, &Record) -> fmt::Result` are
+//! formatters, and this module has presets for the common layouts. Event Viewer already shows
+//! the level and time of every event, so none of the presets repeat them, except for [`Json`]
+//! which is meant to be read by other programs.
+
+use std::fmt::{self, Write};
+
+use log::Record;
+
+mod json;
+
+pub use self::json::{Json, Timestamp};
+
+/// Writes the message for a record into a buffer
+pub trait Formatter: Send + Sync {
+    /// Appends the message for `record` to `buf`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if formatting one of the arguments of the record fails
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result;
+
+    /// Returns true if the messages are meant to be parsed, like those of [`Json`]
+    ///
+    /// Such messages can't be cut short without breaking them, so when an event is too large the
+    /// [`Oversize::Truncate`](crate::Oversize::Truncate) policy cuts the message of the record
+    /// before it is formatted instead. Defaults to false.
+    fn is_structured(&self) -> bool {
+        false
+    }
+}
+
+impl<F> Formatter for F
+where
+    F: Fn(&mut String, &Record) -> fmt::Result + Send + Sync,
+{
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        self(buf, record)
+    }
+}
+
+/// Only the message itself: `disk full`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Message;
+
+impl Formatter for Message {
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        write!(buf, "{}", record.args())
+    }
+}
+
+/// The message prefixed by its module path: `my_service::db: disk full`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModulePath;
+
+impl Formatter for ModulePath {
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        let module = record.module_path().unwrap_or_else(|| record.target());
+        write!(buf, "{}: {}", module, record.args())
+    }
+}
+
+/// The message prefixed by its source location: `src/db.rs(42): disk full`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileLine;
+
+impl Formatter for FileLine {
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        write!(
+            buf,
+            "{}({}): {}",
+            record.file().unwrap_or("<unknown>"),
+            record.line().unwrap_or(0),
+            record.args()
+        )
+    }
+}
+
+/// The message prefixed by the name of the logging thread, or its ID if it has no name:
+/// `[worker-3] disk full`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Thread;
+
+impl Formatter for Thread {
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        let thread = std::thread::current();
+        match thread.name() {
+            Some(name) => write!(buf, "[{}] {}", name, record.args()),
+            None => write!(buf, "[{:?}] {}", thread.id(), record.args()),
+        }
+    }
+}
+
+/// The format used when none is configured: `src/db.rs(42): WARN - disk full`
+pub(crate) fn default_format(buf: &mut String, record: &Record) -> fmt::Result {
+    write!(
+        buf,
+        "{}({}): {} - {}",
+        record.file().unwrap_or("<unknown>"),
+        record.line().unwrap_or(0),
+        record.level(),
+        record.args()
+    )
+}
@@ -0,0 +1,231 @@
+//! The JSON formatter
+
+use std::fmt::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::kv::{Key, Value, VisitSource};
+use log::Record;
+
+use super::Formatter;
+
+/// Every part of the record as one JSON object, for pipelines that parse the event body
+///
+/// The object has the keys `level`, `target`, `module`, `file`, `line`, `timestamp`, `thread`
+/// and `message`, plus a `fields` object holding the key-values of the record. `module`, `file`
+/// and `line` are `null` if they aren't known. The timestamp is in RFC 3339 format, in UTC, see
+/// [`Timestamp`].
+///
+/// ```text
+/// {"level":"WARN","target":"my_service::db","module":"my_service::db","file":"src/db.rs",
+/// "line":42,"timestamp":"2026-10-18T09:30:00.125Z","thread":"main","message":"disk full",
+/// "fields":{"free_bytes":0}}
+/// ```
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{format, Builder, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Json)
+///     .sink(sink.clone())
+///     .build();
+///
+/// let kvs = [("path", "C:\\data")];
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("disk \"C:\" full"))
+///         .level(log::Level::Warn)
+///         .target("my_service::db")
+///         .key_values(&kvs)
+///         .build(),
+/// );
+///
+/// let json = &sink.events()[0].strings[0];
+/// assert!(json.starts_with(r#"{"level":"WARN","target":"my_service::db","module":null,"#));
+/// assert!(json.ends_with(r#""message":"disk \"C:\" full","fields":{"path":"C:\\data"}}"#));
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Formatter for Json {
+    fn format(&self, buf: &mut String, record: &Record) -> fmt::Result {
+        buf.push_str("{\"level\":");
+        write_str(buf, record.level().as_str())?;
+        buf.push_str(",\"target\":");
+        write_str(buf, record.target())?;
+        buf.push_str(",\"module\":");
+        write_opt_str(buf, record.module_path())?;
+        buf.push_str(",\"file\":");
+        write_opt_str(buf, record.file())?;
+        buf.push_str(",\"line\":");
+        match record.line() {
+            Some(line) => write!(buf, "{}", line)?,
+            None => buf.push_str("null"),
+        }
+        buf.push_str(",\"timestamp\":\"");
+        write!(buf, "{}", Timestamp(SystemTime::now()))?;
+        buf.push_str("\",\"thread\":");
+        let thread = std::thread::current();
+        match thread.name() {
+            Some(name) => write_str(buf, name)?,
+            None => write_str(buf, &format!("{:?}", thread.id()))?,
+        }
+        buf.push_str(",\"message\":");
+        write_display(buf, record.args())?;
+        buf.push_str(",\"fields\":{");
+        let mut fields = Fields { buf, first: true };
+        record
+            .key_values()
+            .visit(&mut fields)
+            .map_err(|_| fmt::Error)?;
+        buf.push_str("}}");
+        Ok(())
+    }
+
+    fn is_structured(&self) -> bool {
+        true
+    }
+}
+
+struct Fields<'a> {
+    buf: &'a mut String,
+    first: bool,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        if !self.first {
+            self.buf.push(',');
+        }
+        self.first = false;
+        write_str(self.buf, key.as_str())?;
+        self.buf.push(':');
+        write_value(self.buf, &value)?;
+        Ok(())
+    }
+}
+
+/// Writes `value` as a JSON boolean or number where possible, and as a string otherwise
+fn write_value(buf: &mut String, value: &Value) -> fmt::Result {
+    if let Some(b) = value.to_bool() {
+        write!(buf, "{}", b)
+    } else if let Some(n) = value.to_i64() {
+        write!(buf, "{}", n)
+    } else if let Some(n) = value.to_u64() {
+        write!(buf, "{}", n)
+    } else if let Some(n) = value.to_f64().filter(|n| n.is_finite()) {
+        write!(buf, "{}", n)
+    } else {
+        write_display(buf, value)
+    }
+}
+
+fn write_opt_str(buf: &mut String, s: Option<&str>) -> fmt::Result {
+    match s {
+        Some(s) => write_str(buf, s),
+        None => {
+            buf.push_str("null");
+            Ok(())
+        }
+    }
+}
+
+fn write_str(buf: &mut String, s: &str) -> fmt::Result {
+    write_display(buf, &s)
+}
+
+/// Writes `value` as a JSON string, escaping it while it is formatted
+fn write_display(buf: &mut String, value: &dyn fmt::Display) -> fmt::Result {
+    buf.push('"');
+    write!(Escape(buf), "{}", value)?;
+    buf.push('"');
+    Ok(())
+}
+
+struct Escape<'a>(&'a mut String);
+
+impl Write for Escape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                c if c < ' ' => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.push(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A time written as an RFC 3339 UTC timestamp with millisecond precision, as used by [`Json`]
+///
+/// Times before the Unix epoch are written as the epoch.
+///
+/// # Example
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use win_service_logger::format::Timestamp;
+///
+/// let at = |secs, millis| {
+///     let time = UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis);
+///     Timestamp(time).to_string()
+/// };
+/// assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+/// // A leap day, and the second before the next day
+/// assert_eq!(at(1_709_251_199, 0), "2024-02-29T23:59:59.000Z");
+/// // 2100 is divisible by 100 but not by 400, so it has no February 29th
+/// assert_eq!(at(4_107_499_200, 0), "2100-02-28T12:00:00.000Z");
+/// assert_eq!(at(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+/// // Milliseconds are zero padded, and anything finer is cut off
+/// assert_eq!(at(0, 7), "1970-01-01T00:00:00.007Z");
+/// assert_eq!(
+///     Timestamp(UNIX_EPOCH + Duration::from_nanos(999_999_999)).to_string(),
+///     "1970-01-01T00:00:00.999Z"
+/// );
+/// assert_eq!(
+///     Timestamp(UNIX_EPOCH - Duration::from_secs(1)).to_string(),
+///     "1970-01-01T00:00:00.000Z"
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp(pub SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        // Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3_600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
@@ -0,0 +1,299 @@
+//! Registering event sources, and registering them again when something goes wrong
+//!
+//! `RegisterEventSourceA` fails while the EventLog service isn't running, for example early
+//! during boot, and a handle stops working when the service restarts. [`HandleManager`] wraps
+//! an [`EventSource`], the raw register, report and deregister calls, and keeps a working
+//! handle around:
+//!
+//! - the source is registered when the first event is written
+//! - when registering fails, it is tried again on a later event once the [`Backoff`] delay has
+//!   passed, with the delay doubling after every failure
+//! - when writing an event fails, the source is registered again and the event written once
+//!   more, which recovers from a restart of the EventLog service
+//!
+//! [`EventLogSink`](crate::EventLogSink) is a `HandleManager` over the Win32 calls. Other
+//! sources, such as fakes in tests, work the same way on every platform.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use crate::{Event, EventSink};
+
+/// The calls a [`HandleManager`] makes to write events
+pub trait EventSource: Send + Sync {
+    /// A registered source, such as the `HANDLE` from `RegisterEventSourceA`
+    type Handle: Send + Sync;
+
+    /// Registers the source
+    ///
+    /// # Errors
+    ///
+    /// Returns the OS error if the source can't be registered
+    fn register(&self) -> io::Result<Self::Handle>;
+
+    /// Writes `event` through `handle`
+    ///
+    /// # Errors
+    ///
+    /// Returns the OS error if the event could not be written
+    fn report(&self, handle: &Self::Handle, event: &Event) -> io::Result<()>;
+
+    /// Releases `handle`
+    fn deregister(&self, handle: Self::Handle);
+}
+
+/// How long a [`HandleManager`] waits before registering again after a failure
+///
+/// The first retry waits `initial`, and every failure after that doubles the delay up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+
+    /// The delay after `failures` failed registrations in a row
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.initial
+            .checked_mul(1 << doublings)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+impl Default for Backoff {
+    /// Starts at one second and waits at most a minute
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+enum State<H> {
+    Unregistered,
+    /// `generation` counts registrations, so threads that saw the same write failure only
+    /// register once
+    Registered {
+        handle: H,
+        generation: u64,
+    },
+    Failed {
+        error: io::Error,
+        failures: u32,
+        retry_at: Instant,
+    },
+    Closed,
+}
+
+/// An [`EventSink`] which registers an [`EventSource`] as needed, see the module documentation
+///
+/// # Example
+///
+/// ```
+/// use std::io;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::time::Duration;
+///
+/// use win_service_logger::{Backoff, Event, EventSink, EventSource, EventType, HandleManager};
+///
+/// /// An event log service which is down until `up` is set
+/// #[derive(Default)]
+/// struct Service {
+///     up: AtomicBool,
+/// }
+///
+/// impl EventSource for Service {
+///     type Handle = ();
+///
+///     fn register(&self) -> io::Result<()> {
+///         match self.up.load(Ordering::SeqCst) {
+///             true => Ok(()),
+///             false => Err(io::Error::from_raw_os_error(1722)),
+///         }
+///     }
+///
+///     fn report(&self, _: &(), _: &Event) -> io::Result<()> {
+///         Ok(())
+///     }
+///
+///     fn deregister(&self, _: ()) {}
+/// }
+///
+/// let sink = HandleManager::new(Service::default())
+///     .backoff(Backoff::new(Duration::ZERO, Duration::ZERO));
+/// let event = Event::new(EventType::Information, "starting");
+///
+/// assert!(sink.report(&event).is_err());
+/// sink.source().up.store(true, Ordering::SeqCst);
+/// assert!(sink.report(&event).is_ok());
+/// ```
+pub struct HandleManager<S: EventSource> {
+    source: S,
+    backoff: Backoff,
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+    state: RwLock<State<S::Handle>>,
+    /// The generation of the next registration
+    generation: AtomicU64,
+}
+
+impl<S: EventSource> HandleManager<S> {
+    /// Creates a manager which registers `source` when the first event is written
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            backoff: Backoff::default(),
+            clock: Box::new(Instant::now),
+            state: RwLock::new(State::Unregistered),
+            generation: Default::default(),
+        }
+    }
+
+    /// Sets how long to wait before registering again after a failure
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets where the current time comes from when deciding whether to retry, `Instant::now`
+    /// by default
+    ///
+    /// Lets tests step through the backoff without waiting for it.
+    pub fn clock(mut self, now: impl Fn() -> Instant + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(now);
+        self
+    }
+
+    /// The wrapped source
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State<S::Handle>> {
+        // The state is only ever replaced as a whole, so it can't be left half written
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State<S::Handle>> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes `event` with the current handle, registering first if there is none
+    ///
+    /// Returns the generation of the handle used, so a failed write can be retried with a new
+    /// handle.
+    fn try_report(&self, event: &Event) -> Result<(), (io::Error, Option<u64>)> {
+        {
+            let state = self.read();
+            match &*state {
+                State::Registered { handle, generation } => {
+                    return self
+                        .source
+                        .report(handle, event)
+                        .map_err(|err| (err, Some(*generation)));
+                }
+                State::Failed {
+                    error, retry_at, ..
+                } if (self.clock)() < *retry_at => return Err((copy_error(error), None)),
+                State::Closed => return Err((closed(), None)),
+                State::Unregistered | State::Failed { .. } => {}
+            }
+        }
+        self.reregister(None).map_err(|err| (err, None))?;
+        self.try_report(event)
+    }
+
+    /// Registers the source, replacing the handle of generation `stale` if it is still current
+    fn reregister(&self, stale: Option<u64>) -> io::Result<()> {
+        let mut state = self.write();
+        let failures = match &*state {
+            // Another thread already replaced the stale handle
+            State::Registered { generation, .. } if Some(*generation) != stale => return Ok(()),
+            State::Failed {
+                error, retry_at, ..
+            } if (self.clock)() < *retry_at => return Err(copy_error(error)),
+            State::Failed { failures, .. } => *failures,
+            State::Closed => return Err(closed()),
+            State::Unregistered | State::Registered { .. } => 0,
+        };
+        if let State::Registered { handle, .. } =
+            std::mem::replace(&mut *state, State::Unregistered)
+        {
+            self.source.deregister(handle);
+        }
+        match self.source.register() {
+            Ok(handle) => {
+                let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+                *state = State::Registered { handle, generation };
+                Ok(())
+            }
+            Err(error) => {
+                let failures = failures.saturating_add(1);
+                let result = copy_error(&error);
+                *state = State::Failed {
+                    error,
+                    failures,
+                    retry_at: retry_at((self.clock)(), self.backoff.delay(failures)),
+                };
+                Err(result)
+            }
+        }
+    }
+}
+
+impl<S: EventSource> EventSink for HandleManager<S> {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        match self.try_report(event) {
+            Ok(()) => Ok(()),
+            // The handle may have gone stale, try once more with a new one
+            Err((_, Some(generation))) => {
+                self.reregister(Some(generation))?;
+                self.try_report(event).map_err(|(err, _)| err)
+            }
+            Err((err, None)) => Err(err),
+        }
+    }
+
+    /// Deregisters the source, later events fail with [`io::ErrorKind::NotConnected`]
+    fn close(&self) {
+        let mut state = self.write();
+        if let State::Registered { handle, .. } = std::mem::replace(&mut *state, State::Closed) {
+            self.source.deregister(handle);
+        }
+    }
+}
+
+impl<S: EventSource> Drop for HandleManager<S> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// `io::Error` isn't `Clone`, keep the parts that matter for reporting
+fn copy_error(error: &io::Error) -> io::Error {
+    match error.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(error.kind(), error.to_string()),
+    }
+}
+
+/// The time to register again at, `delay` from `now`
+fn retry_at(now: Instant, delay: Duration) -> Instant {
+    // Delays too far in the future for `Instant` are cut to a day
+    now.checked_add(delay)
+        .or_else(|| now.checked_add(Duration::from_secs(24 * 60 * 60)))
+        .unwrap_or(now)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the event source was closed")
+}
@@ -0,0 +1,70 @@
+//! Choosing the event ID and category of a record
+//!
+//! A record can set both explicitly through the structured key-values [`EVENT_ID_KEY`] and
+//! [`CATEGORY_KEY`], for example `log::error!(event_id = 1001; "disk full")`. Otherwise the
+//! event ID is taken from the level of the record and the category from its target, as
+//! configured on the [`Builder`](crate::Builder). Both default to 0.
+
+use log::kv::{Key, Value};
+use log::{Level, Record};
+
+/// Name of the key-value that sets the event ID of a record
+pub const EVENT_ID_KEY: &str = "event_id";
+
+/// Name of the key-value that sets the category of a record
+pub const CATEGORY_KEY: &str = "category";
+
+/// The configured event ID and category mapping
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventIds {
+    /// Event IDs indexed by `Level as usize - 1`
+    levels: [u32; 5],
+    /// Target prefixes and their categories, sorted so that the longest prefix comes first
+    categories: Vec<(String, u16)>,
+}
+
+impl EventIds {
+    pub(crate) fn set_level(&mut self, level: Level, event_id: u32) {
+        self.levels[level as usize - 1] = event_id;
+    }
+
+    pub(crate) fn set_category(&mut self, target: String, category: u16) {
+        match self.categories.iter_mut().find(|(name, _)| *name == target) {
+            Some(entry) => entry.1 = category,
+            None => {
+                self.categories.push((target, category));
+                self.categories
+                    .sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+            }
+        }
+    }
+
+    /// Returns the event ID for `record`
+    pub(crate) fn event_id(&self, record: &Record) -> u32 {
+        key_value(record, EVENT_ID_KEY)
+            .and_then(|id| u32::try_from(id).ok())
+            .unwrap_or(self.levels[record.level() as usize - 1])
+    }
+
+    /// Returns the category for `record`
+    pub(crate) fn category(&self, record: &Record) -> u16 {
+        key_value(record, CATEGORY_KEY)
+            .and_then(|category| u16::try_from(category).ok())
+            .unwrap_or_else(|| {
+                self.categories
+                    .iter()
+                    .find(|(name, _)| crate::filter::is_module_prefix(name, record.target()))
+                    .map_or(0, |(_, category)| *category)
+            })
+    }
+}
+
+/// Reads the key-value `key` of `record` as an integer
+///
+/// Both integer values and strings holding an integer are accepted
+fn key_value(record: &Record, key: &str) -> Option<u64> {
+    let value: Value = record.key_values().get(Key::from_str(key))?;
+    value
+        .to_u64()
+        .or_else(|| value.to_borrowed_str()?.trim().parse().ok())
+}
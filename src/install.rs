@@ -0,0 +1,608 @@
+//! Registering event sources and logs
+//!
+//! Windows only knows how to show events from a source that is registered under
+//! `HKLM\SYSTEM\CurrentControlSet\Services\EventLog\<log>\<source>`. Unregistered sources are
+//! shown in the Application log without a description. [`install_event_source`] creates that
+//! key, usually from a service's installer, and [`uninstall_event_source`] removes it again.
+//!
+//! Sources don't have to go into the shared Application log. [`install_event_log`] creates a
+//! dedicated log, with its own size limit and retention policy, that sources can then be
+//! registered in. Windows looks up the log of a source by its name, so a
+//! [`Logger`](crate::Logger) writes to the right log without further configuration.
+//!
+//! Every registry write goes through the [`Registry`] trait. [`LocalMachine`] is the real
+//! registry on Windows, while [`MemoryRegistry`] can be used to check what an installer writes
+//! on any platform.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The key under `HKEY_LOCAL_MACHINE` that holds every event log and its sources
+pub const EVENT_LOG_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog";
+
+/// `EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE`, the event types
+/// a [`Logger`](crate::Logger) reports
+const TYPES_SUPPORTED: u32 = 0x0007;
+
+/// Logs that are part of Windows and must not be removed
+const BUILTIN_LOGS: [&str; 3] = ["Application", "Security", "System"];
+
+/// What happens when an event log reaches its maximum size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    /// The oldest events are overwritten
+    OverwriteAsNeeded,
+    /// Only events older than the given age are overwritten, newer events are dropped
+    OverwriteOlderThan(Duration),
+    /// New events are dropped until the log is cleared manually
+    DoNotOverwrite,
+    /// The log is archived to a new file and then cleared
+    Archive,
+}
+
+/// Settings of a custom event log
+///
+/// # Example
+///
+/// ```
+/// use win_service_logger::install::{
+///     install_event_log_with, install_event_source_with, LogOptions, MemoryRegistry, RegValue,
+///     Retention,
+/// };
+///
+/// let registry = MemoryRegistry::new();
+/// let options = LogOptions::new()
+///     .max_size(64 * 1024 * 1024)
+///     .retention(Retention::Archive);
+/// install_event_log_with(&registry, "MyCompany-Agent", &options).unwrap();
+/// install_event_source_with(&registry, "Agent", "MyCompany-Agent", "agent.exe".as_ref(), 0)
+///     .unwrap();
+///
+/// let key = r"SYSTEM\CurrentControlSet\Services\EventLog\MyCompany-Agent";
+/// assert_eq!(registry.value(key, "MaxSize"), Some(RegValue::Dword(64 * 1024 * 1024)));
+/// assert_eq!(registry.value(key, "AutoBackupLogFiles"), Some(RegValue::Dword(1)));
+/// assert!(registry.contains_key(&format!(r"{}\Agent", key)));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogOptions {
+    max_size: u32,
+    retention: Retention,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogOptions {
+    /// A 20 MB log which overwrites its oldest events, the Windows defaults
+    pub fn new() -> Self {
+        Self {
+            max_size: 20 * 1024 * 1024,
+            retention: Retention::OverwriteAsNeeded,
+        }
+    }
+
+    /// Sets the maximum size of the log file in bytes
+    ///
+    /// The size is rounded up to a multiple of 64 KB, the smallest size Windows accepts.
+    pub fn max_size(mut self, bytes: u32) -> Self {
+        const UNIT: u32 = 64 * 1024;
+        self.max_size = bytes.max(UNIT).div_ceil(UNIT).saturating_mul(UNIT);
+        self
+    }
+
+    /// Sets what happens when the log is full
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// The `Retention` and `AutoBackupLogFiles` registry values
+    fn retention_values(&self) -> (u32, u32) {
+        match self.retention {
+            Retention::OverwriteAsNeeded => (0, 0),
+            Retention::OverwriteOlderThan(age) => {
+                // u32::MAX means "never overwrite", so stop one second short of it
+                (age.as_secs().clamp(1, u64::from(u32::MAX - 1)) as u32, 0)
+            }
+            Retention::DoNotOverwrite => (u32::MAX, 0),
+            Retention::Archive => (u32::MAX, 1),
+        }
+    }
+}
+
+/// A registry value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegValue {
+    /// `REG_SZ`
+    String(String),
+    /// `REG_EXPAND_SZ`, a string that may contain `%VARIABLE%` references
+    ExpandString(String),
+    /// `REG_DWORD`
+    Dword(u32),
+}
+
+/// Write access to keys under `HKEY_LOCAL_MACHINE`
+///
+/// Paths are relative to `HKEY_LOCAL_MACHINE` and, like the registry itself, case insensitive.
+pub trait Registry {
+    /// Creates the key `path`, along with any missing parent keys
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be created
+    fn create_key(&self, path: &str) -> io::Result<()>;
+
+    /// Sets the value `name` of the existing key `path`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key doesn't exist or can't be written
+    fn set_value(&self, path: &str, name: &str, value: &RegValue) -> io::Result<()>;
+
+    /// Deletes the key `path` with all of its values and subkeys
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key doesn't exist or can't be deleted
+    fn delete_key(&self, path: &str) -> io::Result<()>;
+
+    /// Lists the names of the direct subkeys of `path`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key doesn't exist or can't be read
+    fn subkeys(&self, path: &str) -> io::Result<Vec<String>>;
+}
+
+impl<R: Registry + ?Sized> Registry for &R {
+    fn create_key(&self, path: &str) -> io::Result<()> {
+        (**self).create_key(path)
+    }
+
+    fn set_value(&self, path: &str, name: &str, value: &RegValue) -> io::Result<()> {
+        (**self).set_value(path, name, value)
+    }
+
+    fn delete_key(&self, path: &str) -> io::Result<()> {
+        (**self).delete_key(path)
+    }
+
+    fn subkeys(&self, path: &str) -> io::Result<Vec<String>> {
+        (**self).subkeys(path)
+    }
+}
+
+/// Registers the event source `source` in the event log `log` of the local computer
+///
+/// `message_file` is the executable or DLL holding the message table for the source, see the
+/// [`message_table`](crate::message_table) module, and `categories` is the number of
+/// categories in it. Writing to `HKEY_LOCAL_MACHINE` requires administrator rights.
+///
+/// # Errors
+///
+/// Fails if `source` is already registered in a different log, if `log` doesn't exist, if a
+/// name is invalid or if the registry can't be written
+#[cfg(windows)]
+pub fn install_event_source(
+    source: &str,
+    log: &str,
+    message_file: &Path,
+    categories: u32,
+) -> io::Result<()> {
+    install_event_source_with(LocalMachine, source, log, message_file, categories)
+}
+
+/// Removes the registration of the event source `source` from the local computer
+///
+/// # Errors
+///
+/// Fails if `source` isn't registered or the registry can't be written
+#[cfg(windows)]
+pub fn uninstall_event_source(source: &str) -> io::Result<()> {
+    uninstall_event_source_with(LocalMachine, source)
+}
+
+/// Creates the custom event log `log` on the local computer
+///
+/// Sources are added to the log with [`install_event_source`]. Writing to
+/// `HKEY_LOCAL_MACHINE` requires administrator rights.
+///
+/// # Errors
+///
+/// Fails if the name is invalid, if its first 8 characters match those of another log, which
+/// Windows doesn't support, or if the registry can't be written
+#[cfg(windows)]
+pub fn install_event_log(log: &str, options: &LogOptions) -> io::Result<()> {
+    install_event_log_with(LocalMachine, log, options)
+}
+
+/// Removes the custom event log `log`, and every source registered in it, from the local
+/// computer
+///
+/// The log file itself is left in place.
+///
+/// # Errors
+///
+/// Fails if `log` doesn't exist, is one of the logs built into Windows, or if the registry
+/// can't be written
+#[cfg(windows)]
+pub fn uninstall_event_log(log: &str) -> io::Result<()> {
+    uninstall_event_log_with(LocalMachine, log)
+}
+
+/// Creates the custom event log `log` in `registry`
+///
+/// Writes `MaxSize`, `Retention`, `AutoBackupLogFiles` and `File`. Installing a log again
+/// updates its settings.
+///
+/// # Errors
+///
+/// See [`install_event_log`]
+pub fn install_event_log_with(
+    registry: impl Registry,
+    log: &str,
+    options: &LogOptions,
+) -> io::Result<()> {
+    check_name("log", log)?;
+    let prefix = |name: &str| name.chars().take(8).collect::<String>().to_lowercase();
+    let conflict = registry
+        .subkeys(EVENT_LOG_KEY)?
+        .into_iter()
+        .find(|other| !other.eq_ignore_ascii_case(log) && prefix(other) == prefix(log));
+    if let Some(other) = conflict {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "the first 8 characters of log name {} match the existing log {}",
+                log, other
+            ),
+        ));
+    }
+
+    let (retention, auto_backup) = options.retention_values();
+    // Windows names log files after their log, with `/` escaped as `%4`
+    let file = format!(
+        r"%SystemRoot%\System32\winevt\Logs\{}.evtx",
+        log.replace('/', "%4")
+    );
+    let key = format!(r"{}\{}", EVENT_LOG_KEY, log);
+    registry.create_key(&key)?;
+    registry.set_value(&key, "MaxSize", &RegValue::Dword(options.max_size))?;
+    registry.set_value(&key, "Retention", &RegValue::Dword(retention))?;
+    registry.set_value(&key, "AutoBackupLogFiles", &RegValue::Dword(auto_backup))?;
+    registry.set_value(&key, "File", &RegValue::ExpandString(file))
+}
+
+/// Removes the custom event log `log`, and every source registered in it, from `registry`
+///
+/// # Errors
+///
+/// See [`uninstall_event_log`]
+pub fn uninstall_event_log_with(registry: impl Registry, log: &str) -> io::Result<()> {
+    check_name("log", log)?;
+    if BUILTIN_LOGS.iter().any(|b| b.eq_ignore_ascii_case(log)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is built into Windows and can't be removed", log),
+        ));
+    }
+    registry.delete_key(&format!(r"{}\{}", EVENT_LOG_KEY, log))
+}
+
+/// Registers the event source `source` in the event log `log` of `registry`
+///
+/// Writes `EventMessageFile` and `TypesSupported`, and `CategoryMessageFile` and
+/// `CategoryCount` if there are any categories. Installing a source again updates its values.
+///
+/// # Errors
+///
+/// See [`install_event_source`]
+///
+/// # Example
+///
+/// ```
+/// use std::path::Path;
+/// use win_service_logger::install::{
+///     install_event_source_with, uninstall_event_source_with, MemoryRegistry, RegValue,
+/// };
+///
+/// let registry = MemoryRegistry::new();
+/// let file = Path::new(r"C:\Program Files\My Service\service.exe");
+/// install_event_source_with(&registry, "My Service", "Application", file, 2).unwrap();
+///
+/// let key = r"SYSTEM\CurrentControlSet\Services\EventLog\Application\My Service";
+/// assert_eq!(registry.value(key, "TypesSupported"), Some(RegValue::Dword(7)));
+/// assert_eq!(registry.value(key, "CategoryCount"), Some(RegValue::Dword(2)));
+///
+/// uninstall_event_source_with(&registry, "My Service").unwrap();
+/// assert!(!registry.contains_key(key));
+/// ```
+pub fn install_event_source_with(
+    registry: impl Registry,
+    source: &str,
+    log: &str,
+    message_file: &Path,
+    categories: u32,
+) -> io::Result<()> {
+    check_name("source", source)?;
+    check_name("log", log)?;
+    match find_source(&registry, source)? {
+        Some(existing) if !existing.eq_ignore_ascii_case(log) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "event source {} is registered in the {} log",
+                    source, existing
+                ),
+            ));
+        }
+        _ => {}
+    }
+
+    if !registry
+        .subkeys(EVENT_LOG_KEY)?
+        .iter()
+        .any(|l| l.eq_ignore_ascii_case(log))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("event log {} does not exist", log),
+        ));
+    }
+
+    let key = format!(r"{}\{}\{}", EVENT_LOG_KEY, log, source);
+    let message_file = RegValue::ExpandString(message_file.to_string_lossy().into_owned());
+    registry.create_key(&key)?;
+    registry.set_value(&key, "EventMessageFile", &message_file)?;
+    registry.set_value(&key, "TypesSupported", &RegValue::Dword(TYPES_SUPPORTED))?;
+    if categories > 0 {
+        registry.set_value(&key, "CategoryMessageFile", &message_file)?;
+        registry.set_value(&key, "CategoryCount", &RegValue::Dword(categories))?;
+    }
+    Ok(())
+}
+
+/// Removes the registration of the event source `source` from `registry`
+///
+/// # Errors
+///
+/// See [`uninstall_event_source`]
+pub fn uninstall_event_source_with(registry: impl Registry, source: &str) -> io::Result<()> {
+    check_name("source", source)?;
+    match find_source(&registry, source)? {
+        Some(log) => registry.delete_key(&format!(r"{}\{}\{}", EVENT_LOG_KEY, log, source)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("event source {} is not registered", source),
+        )),
+    }
+}
+
+/// Returns the name of the log `source` is registered in
+fn find_source(registry: &impl Registry, source: &str) -> io::Result<Option<String>> {
+    for log in registry.subkeys(EVENT_LOG_KEY)? {
+        // Some logs, like Security, can't be read without extra privileges
+        let sources = match registry.subkeys(&format!(r"{}\{}", EVENT_LOG_KEY, log)) {
+            Ok(sources) => sources,
+            Err(_) => continue,
+        };
+        if sources.iter().any(|s| s.eq_ignore_ascii_case(source)) {
+            return Ok(Some(log));
+        }
+    }
+    Ok(None)
+}
+
+fn check_name(kind: &str, name: &str) -> io::Result<()> {
+    if name.is_empty() || name.contains('\\') || name.contains('\0') {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid {} name {:?}", kind, name),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// An in-memory [`Registry`]
+///
+/// Clones share the same keys. It starts out with an empty [`EVENT_LOG_KEY`] and an
+/// `Application` log, like a fresh Windows installation.
+#[derive(Debug, Clone)]
+pub struct MemoryRegistry {
+    keys: Arc<Mutex<BTreeMap<String, MemoryKey>>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryKey {
+    /// The path with its original case, keys in the map are lowercase
+    path: String,
+    values: BTreeMap<String, RegValue>,
+}
+
+impl Default for MemoryRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryRegistry {
+    pub fn new() -> Self {
+        let registry = Self {
+            keys: Arc::default(),
+        };
+        let _ = registry.create_key(&format!(r"{}\Application", EVENT_LOG_KEY));
+        registry
+    }
+
+    /// Returns true if the key `path` exists
+    pub fn contains_key(&self, path: &str) -> bool {
+        self.lock().contains_key(&path.to_ascii_lowercase())
+    }
+
+    /// Returns the value `name` of the key `path`
+    pub fn value(&self, path: &str, name: &str) -> Option<RegValue> {
+        let keys = self.lock();
+        let key = keys.get(&path.to_ascii_lowercase())?;
+        key.values
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, MemoryKey>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn create_key(&self, path: &str) -> io::Result<()> {
+        let mut keys = self.lock();
+        let mut end = 0;
+        for part in path.split('\\') {
+            end += part.len();
+            let parent = &path[..end];
+            keys.entry(parent.to_ascii_lowercase())
+                .or_insert_with(|| MemoryKey {
+                    path: parent.to_owned(),
+                    values: BTreeMap::new(),
+                });
+            end += 1;
+        }
+        Ok(())
+    }
+
+    fn set_value(&self, path: &str, name: &str, value: &RegValue) -> io::Result<()> {
+        let mut keys = self.lock();
+        let key = keys
+            .get_mut(&path.to_ascii_lowercase())
+            .ok_or_else(|| not_found(path))?;
+        key.values.retain(|n, _| !n.eq_ignore_ascii_case(name));
+        key.values.insert(name.to_owned(), value.clone());
+        Ok(())
+    }
+
+    fn delete_key(&self, path: &str) -> io::Result<()> {
+        let path = path.to_ascii_lowercase();
+        let prefix = format!("{}\\", path);
+        let mut keys = self.lock();
+        if keys.remove(&path).is_none() {
+            return Err(not_found(&path));
+        }
+        keys.retain(|p, _| !p.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn subkeys(&self, path: &str) -> io::Result<Vec<String>> {
+        let keys = self.lock();
+        let path = path.to_ascii_lowercase();
+        if !keys.contains_key(&path) {
+            return Err(not_found(&path));
+        }
+        let prefix = format!("{}\\", path);
+        Ok(keys
+            .iter()
+            .filter_map(|(p, key)| {
+                let name = p.strip_prefix(&prefix)?;
+                (!name.contains('\\')).then(|| key.path[prefix.len()..].to_owned())
+            })
+            .collect())
+    }
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("registry key {} does not exist", path),
+    )
+}
+
+#[cfg(windows)]
+pub use self::win32::LocalMachine;
+
+#[cfg(windows)]
+mod win32 {
+    use std::io;
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::winerror::ERROR_NO_MORE_ITEMS;
+    use winapi::um::winnt::{KEY_READ, KEY_SET_VALUE, KEY_WRITE, REG_DWORD, REG_EXPAND_SZ, REG_SZ};
+    use winapi::um::winreg::{RegDeleteTreeW, RegEnumKeyExW, HKEY_LOCAL_MACHINE};
+
+    use super::{RegValue, Registry};
+    use crate::regkey::{check, wide, Key};
+
+    /// The registry of the local computer
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct LocalMachine;
+
+    impl Registry for LocalMachine {
+        fn create_key(&self, path: &str) -> io::Result<()> {
+            Key::create(path, KEY_WRITE).map(drop)
+        }
+
+        fn set_value(&self, path: &str, name: &str, value: &RegValue) -> io::Result<()> {
+            let key = Key::open(path, KEY_SET_VALUE)?;
+            let (kind, data) = match value {
+                RegValue::String(s) => (REG_SZ, string_bytes(s)?),
+                RegValue::ExpandString(s) => (REG_EXPAND_SZ, string_bytes(s)?),
+                RegValue::Dword(d) => (REG_DWORD, d.to_le_bytes().to_vec()),
+            };
+            key.set_value(name, kind, &data)
+        }
+
+        fn delete_key(&self, path: &str) -> io::Result<()> {
+            let path = wide(path)?;
+            // # Safety:
+            // 1. `path` is a valid null terminated utf-16 string
+            // 2. WinAPI call
+            check(unsafe { RegDeleteTreeW(HKEY_LOCAL_MACHINE, path.as_ptr()) })
+        }
+
+        fn subkeys(&self, path: &str) -> io::Result<Vec<String>> {
+            let key = Key::open(path, KEY_READ)?;
+            let mut names = Vec::new();
+            // Key names are limited to 255 characters
+            let mut name = [0u16; 256];
+            for index in 0.. {
+                let mut len = name.len() as DWORD;
+                // # Safety:
+                // 1. `name` has room for `len` characters
+                // 2. WinAPI call
+                let status = unsafe {
+                    RegEnumKeyExW(
+                        key.raw(),
+                        index,
+                        name.as_mut_ptr(),
+                        &mut len,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                    )
+                };
+                if status as DWORD == ERROR_NO_MORE_ITEMS {
+                    break;
+                }
+                check(status)?;
+                names.push(String::from_utf16_lossy(&name[..len as usize]));
+            }
+            Ok(names)
+        }
+    }
+
+    /// The bytes of `s` as a null terminated utf-16 string
+    fn string_bytes(s: &str) -> io::Result<Vec<u8>> {
+        Ok(wide(s)?
+            .as_slice_with_nul()
+            .iter()
+            .flat_map(|c| c.to_le_bytes())
+            .collect())
+    }
+}
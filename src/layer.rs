@@ -0,0 +1,186 @@
+//! Writing `tracing` events to the event log
+//!
+//! [`EventLogLayer`] is a [`tracing_subscriber::Layer`] which turns every `tracing` event into a
+//! `log` record and hands it to a [`Logger`], so levels, formatting, insertion strings, event IDs
+//! and sinks are configured on the [`Builder`](crate::Builder) as usual.
+//!
+//! The fields of an event become key-values of the record. They can be sent as insertion
+//! strings with [`Field::Key`](crate::Field::Key) and
+//! [`Field::KeyValues`](crate::Field::KeyValues), and the `event_id`, `category`, `raw_data` and
+//! `user_sid` fields work like their key-values.
+//! The message of the record is prefixed with the spans the event is in, with their fields:
+//! `request{id=7}:db: query failed`.
+
+use std::fmt::{self, Write};
+
+use log::Log;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::{EventType, Logger};
+
+/// A [`Layer`] which writes `tracing` events through a [`Logger`]
+///
+/// # Example
+///
+/// ```
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use win_service_logger::{format, Builder, EventLogLayer, Field, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Message)
+///     .fields([Field::Message, Field::Key("path".into())])
+///     .sink(sink.clone())
+///     .build();
+/// let subscriber = tracing_subscriber::registry().with(EventLogLayer::new(logger));
+///
+/// tracing::subscriber::with_default(subscriber, || {
+///     let span = tracing::info_span!("request", id = 7);
+///     let _guard = span.enter();
+///     tracing::warn!(path = "/index.html", "not found");
+/// });
+///
+/// let events = sink.events();
+/// assert_eq!(events[0].strings, ["request{id=7}: not found", "/index.html"]);
+/// ```
+pub struct EventLogLayer {
+    logger: Logger,
+}
+
+impl EventLogLayer {
+    /// Creates a layer which writes events through `logger`
+    pub fn new(logger: Logger) -> Self {
+        Self { logger }
+    }
+}
+
+impl<S> Layer<S> for EventLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = SpanFields(String::new());
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = log_level(*metadata.level());
+        if !self.logger.enabled(
+            &log::Metadata::builder()
+                .level(level)
+                .target(metadata.target())
+                .build(),
+        ) {
+            return;
+        }
+
+        let mut message = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                message.push_str(span.name());
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        // Writing to a String can't fail
+                        let _ = write!(message, "{{{}}}", fields);
+                    }
+                }
+                message.push(':');
+            }
+            if !message.is_empty() {
+                message.push(' ');
+            }
+        }
+        let mut fields = EventFields {
+            message: &mut message,
+            key_values: Vec::new(),
+        };
+        event.record(&mut fields);
+        let key_values: Vec<(&str, &str)> = fields
+            .key_values
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+
+        self.logger.log(
+            &log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .key_values(&key_values.as_slice())
+                .build(),
+        );
+    }
+}
+
+impl From<Level> for EventType {
+    fn from(level: Level) -> Self {
+        log_level(level).into()
+    }
+}
+
+fn log_level(level: Level) -> log::Level {
+    match level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+/// The fields of a span as `key=value`, separated by spaces
+struct SpanFields(String);
+
+impl Visit for SpanFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        // Writing to a String can't fail
+        let _ = write!(self.0, "{}={:?}", field.name(), value);
+    }
+}
+
+/// Collects the message of an event and its other fields as key-values
+struct EventFields<'a> {
+    message: &'a mut String,
+    key_values: Vec<(&'static str, String)>,
+}
+
+impl Visit for EventFields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            // Writing to a String can't fail
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            self.key_values.push((field.name(), format!("{:?}", value)));
+        }
+    }
+}
@@ -6,6 +6,7 @@
 //! extern crate win_service_logger;
 //! #[macro_use] extern crate log;
 //!
+//! # #[cfg(windows)]
 //! fn main() {
 //!     win_service_logger::init();
 //!     trace!("Hello from Rust!");
@@ -13,42 +14,201 @@
 //!     warn!("This will be a warning in Event Viewer!");
 //!     error!("Bad");
 //! }
+//! # #[cfg(not(windows))]
+//! # fn main() {}
+//! ```
+//!
+//! Use a [`Builder`] to configure the source name, levels and message [`format`] at runtime.
+//! Levels can also be given as `RUST_LOG` style directives with [`Builder::parse_filters`] or
+//! [`Builder::parse_env`].
+//!
+//! Parts of a record, including its key-values, can be sent as separate insertion strings with
+//! [`Builder::fields`]. Event IDs and categories can be set per record with the `event_id` and
+//! `category` key-values, or configured per level and target on the [`Builder`]. Binary data is
+//! attached with the `raw_data` key-value, see [`RawData`], and the user an event is logged for
+//! with the `user_sid` key-value or a [`UserSidScope`]. Events too large for the event log are
+//! truncated or split into several events, see [`Oversize`], and NUL characters in messages are
+//! escaped or replaced, see [`NulPolicy`]. Logging never panics on the contents of a record.
+//!
+//! Event sources are registered with [`install_event_source`], either in the Application log or
+//! in a custom log created with [`install_event_log`]. See the [`install`] module.
+//! The [`message_table`] module generates a matching message table resource from a build
+//! script, so Event Viewer can show a proper description for every event ID.
+//!
+//! With the `registry` feature the configuration can also be read, and reloaded, from the
+//! registry key of the event source. See the [`registry`] module. The `tracing` feature adds
+//! [`EventLogLayer`], which writes `tracing` events through a [`Logger`]. See the [`layer`]
+//! module. Likewise the `slog` feature adds [`EventLogDrain`], see the [`drain`] module.
+//!
+//! Events are written through an [`EventSink`]. Outside of Windows, or in tests, a
+//! [`RecordingSink`] can be used in place of the Event Log. The event source is registered again
+//! with a backoff when the EventLog service isn't available, see [`HandleManager`]. Failed
+//! writes are counted in [`Logger::stats`] and can be sent to a fallback sink, see [`Stats`].
+//! Events can also be copied to stderr or a [`RollingFileSink`] with [`Builder::tee`], or to
+//! stderr only when the program runs in a console with [`Builder::detect_console`]. To use the
+//! event log next to other `log` backends, each with its own level, install a [`MultiLogger`].
+//!
+//! With [`Builder::async_queue`] events are written on a background thread, so logging doesn't
+//! wait for the EventLog service. See [`Overflow`] for what happens when the queue is full.
+//! [`shutdown`] writes what is left in the queue and deregisters the event source, so the final
+//! message of a service stop sequence isn't lost.
+//!
+//! [`Builder::rate_limit`] limits how often the same call site or message is written, and
+//! collapses the suppressed records into summaries such as "previous message repeated 4,213
+//! times". See [`RateLimit`].
+
+mod builder;
+mod data;
+#[cfg(feature = "slog")]
+pub mod drain;
+mod fields;
+mod filter;
+pub mod format;
+mod handle;
+mod ids;
+pub mod install;
+#[cfg(feature = "tracing")]
+pub mod layer;
+mod limit;
+pub mod message_table;
+mod multi;
+mod nul;
+mod queue;
+#[cfg(feature = "registry")]
+pub mod registry;
+#[cfg(windows)]
+mod regkey;
+mod sid;
+mod sink;
+mod split;
+mod stats;
+mod writer;
 
-use std::cell::UnsafeCell;
-use std::ffi::CString;
-use std::sync::Once;
+pub use builder::Builder;
+pub use data::{RawData, DEFAULT_RAW_DATA_LIMIT, RAW_DATA_HEX_PREFIX, RAW_DATA_KEY};
+#[cfg(feature = "slog")]
+pub use drain::EventLogDrain;
+pub use fields::Field;
+pub use filter::InvalidDirectives;
+pub use format::Formatter;
+pub use handle::{Backoff, EventSource, HandleManager};
+pub use ids::{CATEGORY_KEY, EVENT_ID_KEY};
+#[cfg(feature = "tracing")]
+pub use layer::EventLogLayer;
+pub use limit::{RateKey, RateLimit};
+pub use multi::MultiLogger;
+pub use nul::NulPolicy;
+pub use queue::Overflow;
+pub use sid::{InvalidSid, Sid, UserSidScope, USER_SID_KEY};
+pub use sink::{
+    has_console, Event, EventSink, EventType, FileSink, RecordingSink, RollingFileSink, StderrSink,
+};
+pub use split::{Oversize, MAX_EVENT_SIZE, MAX_STRING_LEN};
+pub use stats::Stats;
 
-use log::{Level, Metadata, Record};
-use winapi::um::winnt::HANDLE;
+#[cfg(windows)]
+pub use install::{
+    install_event_log, install_event_source, uninstall_event_log, uninstall_event_source,
+};
+#[cfg(windows)]
+pub use sink::EventLogSink;
+
+use std::cell::RefCell;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use log::{Level, LevelFilter, Metadata, Record};
+
+use filter::Filter;
+use ids::EventIds;
+use limit::{Limiter, Summary, Template, Verdict};
+use queue::Queue;
+use writer::Writer;
 
 pub struct Logger {
-    handle: UnsafeCell<HANDLE>,
-    handle_init: Once,
-    log_name: &'static str,
+    writer: Arc<Writer>,
+    queue: Option<Queue>,
+    limiter: Option<Limiter>,
+    filter: Arc<Filter>,
+    ids: EventIds,
+    fields: Vec<Field>,
+    raw_data_limit: usize,
+    oversize: Oversize,
+    nul_policy: NulPolicy,
+    user_sid: Option<Sid>,
+    format: Box<dyn Formatter>,
+    #[cfg(feature = "registry")]
+    registry: Option<registry::RegistryConfig>,
 }
 
-unsafe impl Send for Logger {}
-unsafe impl Sync for Logger {}
+/// The logger installed by [`Logger::try_init`] or [`MultiLogger::try_init`], for [`shutdown`]
+pub(crate) static GLOBAL: OnceLock<&'static dyn ShutdownHook> = OnceLock::new();
+
+/// A global logger which [`shutdown`] can shut down
+pub(crate) trait ShutdownHook: Sync {
+    fn shutdown(&self);
+}
 
-pub static LOGGER: Logger = Logger::new("Rust Application");
+impl ShutdownHook for Logger {
+    fn shutdown(&self) {
+        Logger::shutdown(self);
+    }
+}
+
+/// Shuts the global logger down, see [`Logger::shutdown`]
+///
+/// Call this at the end of a service stop sequence, after its final message. The leaked global
+/// logger is never dropped, so without this its event source stays registered until the process
+/// exits. A global [`MultiLogger`] shuts down each [`Logger`] in it. Does nothing if the global
+/// logger wasn't installed by [`Logger::try_init`], [`MultiLogger::try_init`] or one of the
+/// functions calling them.
+///
+/// # Example
+///
+/// ```
+/// use log::LevelFilter;
+/// use win_service_logger::{Logger, MultiLogger, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// MultiLogger::new()
+///     .logger(Logger::new(sink.clone()), LevelFilter::Info)
+///     .init();
+///
+/// log::info!("service stopped");
+/// win_service_logger::shutdown();
+/// log::info!("too late");
+///
+/// assert_eq!(sink.events().len(), 1);
+/// ```
+pub fn shutdown() {
+    if let Some(logger) = GLOBAL.get() {
+        logger.shutdown();
+    }
+}
 
 /// Initializes the global logger with a windows service logger
 ///
+/// This function leaks a single `Logger` to the heap in order to give a static reference to log
+///
 /// # Panics
 ///
 /// This function will panic if a global logger has already been set
 /// Use [`try_init`] for a fallable function
+#[cfg(windows)]
 pub fn init() {
     try_init().unwrap();
 }
 
 /// Initializes the global logger with a windows service logger
 ///
+/// This function leaks a single `Logger` to the heap in order to give a static reference to log
+///
 /// # Errors
 ///
 /// This function fails if a global logger has already been set
+#[cfg(windows)]
 pub fn try_init() -> Result<(), log::SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Debug))
+    try_init_with_name("Rust Application")
 }
 
 /// Initializes the global logger with a windows service logger.
@@ -58,9 +218,9 @@ pub fn try_init() -> Result<(), log::SetLoggerError> {
 /// # Errors
 ///
 /// This function fails if a global logger has already been set
+#[cfg(windows)]
 pub fn try_init_with_name(name: &'static str) -> Result<(), log::SetLoggerError> {
-    let logger = Box::leak(Box::new(Logger::new(name)));
-    log::set_logger(logger).map(|()| log::set_max_level(log::LevelFilter::Debug))
+    Logger::new(EventLogSink::new(name)).try_init()
 }
 
 /// Initializes the global logger with a windows service logger
@@ -70,99 +230,309 @@ pub fn try_init_with_name(name: &'static str) -> Result<(), log::SetLoggerError>
 /// # Panics
 ///
 /// This function will panic if a global logger has already been set
+#[cfg(windows)]
 pub fn init_with_name(name: &'static str) {
-    let logger = Box::leak(Box::new(Logger::new(name)));
-    log::set_logger(logger)
-        .map(|()| log::set_max_level(log::LevelFilter::Debug))
-        .unwrap();
+    try_init_with_name(name).unwrap();
 }
 
 impl Logger {
-    const fn new(log_name: &'static str) -> Self {
+    /// Creates a logger which writes its events to `sink`
+    ///
+    /// Use [`Logger::builder`] to change the level or format
+    pub fn new(sink: impl EventSink + 'static) -> Self {
+        Self::from_parts(Writer::new(Box::new(sink)), Filter::new(LevelFilter::Trace))
+    }
+
+    /// Returns a [`Builder`] for configuring a logger
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Returns the most verbose level written for modules without their own level
+    pub fn max_level(&self) -> LevelFilter {
+        self.filter.level()
+    }
+
+    /// Changes the most verbose level written for modules without their own level
+    ///
+    /// This takes effect immediately, including on other threads, so it can be used to turn on
+    /// [`Trace`](log::Level::Trace) output in a running service. If this logger is the global
+    /// logger [`log::max_level`] is updated as well.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::{LevelFilter, Log};
+    /// use win_service_logger::{Logger, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Logger::new(sink.clone());
+    /// let record = log::Record::builder()
+    ///     .args(format_args!("verbose"))
+    ///     .level(log::Level::Trace)
+    ///     .build();
+    ///
+    /// logger.set_max_level(LevelFilter::Info);
+    /// logger.log(&record);
+    /// assert!(sink.events().is_empty());
+    ///
+    /// logger.set_max_level(LevelFilter::Trace);
+    /// logger.log(&record);
+    /// assert_eq!(sink.events().len(), 1);
+    /// ```
+    pub fn set_max_level(&self, level: LevelFilter) {
+        self.filter.set_level(level);
+    }
+
+    /// Returns how many events were written, and how many the sink failed to write
+    pub fn stats(&self) -> Stats {
+        self.writer.stats.snapshot()
+    }
+
+    /// Writes the summaries of records suppressed by the [rate limit](Builder::rate_limit) and
+    /// the events still in the [async queue](Builder::async_queue), then flushes and closes the
+    /// sinks, which deregisters the event source
+    ///
+    /// Events logged afterwards are dropped and counted in [`Stats::dropped`]. Calling this
+    /// more than once does nothing. Use [`shutdown`] for the global logger.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Log;
+    /// use win_service_logger::{Logger, RecordingSink};
+    ///
+    /// let sink = RecordingSink::new();
+    /// let logger = Logger::new(sink.clone());
+    /// let record = log::Record::builder()
+    ///     .args(format_args!("service stopped"))
+    ///     .level(log::Level::Info)
+    ///     .build();
+    ///
+    /// logger.log(&record);
+    /// logger.shutdown();
+    /// logger.log(&record);
+    ///
+    /// assert_eq!(sink.events().len(), 1);
+    /// assert_eq!(logger.stats().dropped, 1);
+    /// ```
+    pub fn shutdown(&self) {
+        self.report_dropped();
+        if let Some(limiter) = &self.limiter {
+            let mut summaries = Vec::new();
+            limiter.drain(&mut summaries);
+            for summary in &summaries {
+                self.summarize(summary);
+            }
+        }
+        if let Some(queue) = &self.queue {
+            queue.close();
+        }
+        self.writer.close();
+    }
+
+    /// Re-reads the level and filter directives from the store given to
+    /// [`Builder::registry`]
+    ///
+    /// Does nothing if the logger wasn't configured from the registry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration couldn't be read, or an
+    /// [`InvalidData`](std::io::ErrorKind::InvalidData) error if the level isn't a level or
+    /// the filters contain [invalid directives](InvalidDirectives). The current levels are kept
+    /// in all cases.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use win_service_logger::registry::{MemoryKeyStore, FILTERS_VALUE, LEVEL_VALUE};
+    /// use win_service_logger::{Builder, RecordingSink};
+    ///
+    /// let store = MemoryKeyStore::new();
+    /// let logger = Builder::new()
+    ///     .registry(store.clone())
+    ///     .sink(RecordingSink::new())
+    ///     .build();
+    ///
+    /// store.set(FILTERS_VALUE, "warn,hyper=loud");
+    /// let err = logger.reload().unwrap_err();
+    /// assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    ///
+    /// store.set(FILTERS_VALUE, "hyper=warn");
+    /// store.set(LEVEL_VALUE, "warning");
+    /// let err = logger.reload().unwrap_err();
+    /// assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    /// assert_eq!(logger.max_level(), log::LevelFilter::Trace);
+    /// ```
+    #[cfg(feature = "registry")]
+    pub fn reload(&self) -> std::io::Result<()> {
+        match &self.registry {
+            Some(config) => config.apply(&self.filter),
+            None => Ok(()),
+        }
+    }
+
+    fn from_parts(writer: Writer, filter: Filter) -> Self {
         Self {
-            handle: UnsafeCell::new(std::ptr::null_mut()),
-            log_name,
-            handle_init: Once::new(),
+            writer: Arc::new(writer),
+            queue: None,
+            limiter: None,
+            filter: Arc::new(filter),
+            ids: EventIds::default(),
+            fields: vec![Field::Message],
+            raw_data_limit: DEFAULT_RAW_DATA_LIMIT,
+            oversize: Oversize::default(),
+            nul_policy: NulPolicy::default(),
+            user_sid: None,
+            format: Box::new(format::default_format),
+            #[cfg(feature = "registry")]
+            registry: None,
+        }
+    }
+
+    /// Installs this logger as the global logger
+    ///
+    /// This function leaks the `Logger` to the heap in order to give a static reference to log
+    ///
+    /// # Errors
+    ///
+    /// This function fails if a global logger has already been set
+    pub fn try_init(self) -> Result<(), log::SetLoggerError> {
+        let logger = Box::leak(Box::new(self));
+        log::set_logger(logger)?;
+        logger.filter.set_global();
+        // `set_logger` succeeds only once, so this is the first and only global logger
+        let _ = GLOBAL.set(logger);
+        Ok(())
+    }
+
+    /// Builds the event for `record` without writing it anywhere
+    fn event(&self, record: &Record) -> Event {
+        let mut event = Event {
+            event_type: record.level().into(),
+            category: self.ids.category(record),
+            event_id: self.ids.event_id(record),
+            strings: self.strings(record),
+            raw_data: data::raw_data(record, self.raw_data_limit),
+            user_sid: sid::user_sid(record, self.user_sid.as_ref()),
+        };
+        if self.oversize == Oversize::Truncate && self.format.is_structured() {
+            // Cutting the formatted string short would leave it unreadable
+            split::truncate_message(record, &mut event, |record| self.strings(record));
+        }
+        event
+    }
+
+    /// Formats `record` and lays it out as insertion strings
+    fn strings(&self, record: &Record) -> Vec<String> {
+        thread_local! {
+            static BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+        }
+
+        let mut strings = BUFFER.with(|buffer| {
+            // A formatter which logs itself would find the buffer borrowed, give it a new one
+            let mut fresh = String::new();
+            let mut borrowed = buffer.try_borrow_mut();
+            let msg = match &mut borrowed {
+                Ok(buffer) => &mut **buffer,
+                Err(_) => &mut fresh,
+            };
+            msg.clear();
+            if self.format.format(msg, record).is_err() {
+                // A formatter only fails if one of its arguments does, keep what was written so far
+                msg.push_str("<formatting error>");
+            }
+            fields::insertion_strings(&self.fields, record, msg)
+        });
+        for string in &mut strings {
+            self.nul_policy.apply(string);
+        }
+        strings
+    }
+
+    /// Writes the summary of records suppressed by the rate limit, built like any other record
+    fn summarize(&self, summary: &Summary) {
+        let mut event = summary.with_record(|record| self.event(record));
+        // The record may have been logged on another thread, inside a `UserSidScope`
+        event.user_sid = summary.user_sid().cloned();
+        self.dispatch(event);
+    }
+
+    /// Queues a warning with the number of events the async queue dropped since the last one,
+    /// built like any other record
+    fn report_dropped(&self) {
+        let dropped = match &self.queue {
+            Some(queue) => queue.take_unreported(),
+            None => return,
+        };
+        if dropped > 0 {
+            let event = self.event(
+                &Record::builder()
+                    .args(format_args!(
+                        "{} events were dropped because the log queue was full",
+                        dropped
+                    ))
+                    .level(Level::Warn)
+                    .target(module_path!())
+                    .module_path_static(Some(module_path!()))
+                    .build(),
+            );
+            self.dispatch(event);
+        }
+    }
+
+    /// Splits `event` as configured and writes the parts, or queues them
+    fn dispatch(&self, event: Event) {
+        let marker = self
+            .fields
+            .iter()
+            .position(|field| *field == Field::Message);
+        for event in split::fit(event, self.oversize, marker) {
+            match &self.queue {
+                Some(queue) => queue.push(event),
+                None => self.writer.write(&event),
+            }
         }
     }
 }
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Debug
+        self.filter.enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            // We use a Once and unsafe cell so that we can lazily initialize `self.handle`
-            // We need to have Self in a static, so new must be const
-            // `self.handle` is initialized once and then read multiple times so doing it this way
-            // means we don't need to acquire a mutex every time to read `self.handle`
-            self.handle_init.call_once(|| {
-                let c_str = CString::new(self.log_name).unwrap();
-                // # Safety:
-                // 1. `c_str` is a valid null terminated string
-                // 2. WinAPI call
-                let handle = unsafe {
-                    winapi::um::winbase::RegisterEventSourceA(std::ptr::null_mut(), c_str.as_ptr())
-                };
-                // # Safety.
-                // We are inside a Once's init block therefore we have exclusive access
-                // to self.handle
-                unsafe { *self.handle.get() = handle };
-            });
-            let msg = format!(
-                "{}({}): {} - {}",
-                record.file().unwrap_or("<unknown>"),
-                record.line().unwrap_or(0),
-                record.level(),
-                record.args()
-            );
-
-            let event_type = match record.metadata().level() {
-                Level::Trace => winapi::um::winnt::EVENTLOG_INFORMATION_TYPE,
-                Level::Debug => winapi::um::winnt::EVENTLOG_INFORMATION_TYPE,
-                Level::Info => winapi::um::winnt::EVENTLOG_INFORMATION_TYPE,
-                Level::Warn => winapi::um::winnt::EVENTLOG_WARNING_TYPE,
-                Level::Error => winapi::um::winnt::EVENTLOG_ERROR_TYPE,
-            };
-            let wide_msg = widestring::U16CString::from_str(msg).unwrap();
-            let mut strings = [wide_msg.as_ptr()];
-
-            // # Safety:
-            // 1. The init block has completed so there are no exclusive references to `self.handle`.
-            // 2. The init block has completed so we have established a happens before relationship
-            //    with the initializing thread. Therefore we will see the initialized value
-            let handle = unsafe { *self.handle.get() };
-
-            // # Safety:
-            // 1. strings is a pointer to a null terminated message utf-16 string
-            // 2. The length of strings is 1 and we pass one as the length
-            // 3. WinAPI call
-            unsafe {
-                winapi::um::winbase::ReportEventW(
-                    handle,
-                    event_type,
-                    0,
-                    0,
-                    std::ptr::null_mut(),
-                    1, //length
-                    0,
-                    &mut strings as *mut *const _,
-                    std::ptr::null_mut(),
-                )
+            let mut summaries = Vec::new();
+            let verdict = match &self.limiter {
+                Some(limiter) => limiter.check(
+                    record,
+                    Instant::now(),
+                    || Template::new(record, sid::user_sid(record, self.user_sid.as_ref())),
+                    &mut summaries,
+                ),
+                None => Verdict::Write,
             };
+            self.report_dropped();
+            for summary in &summaries {
+                self.summarize(summary);
+            }
+            match verdict {
+                Verdict::Write => self.dispatch(self.event(record)),
+                Verdict::Suppress => self.writer.stats.suppress(),
+            }
         }
     }
 
-    fn flush(&self) {}
-}
-
-impl Drop for Logger {
-    fn drop(&mut self) {
-        // # Safety:
-        // WinAPI call
-        let handle = *self.handle.get_mut();
-        let _ = unsafe { winapi::um::winbase::DeregisterEventSource(handle) };
+    /// Waits until the events in the [async queue](Builder::async_queue), if any, are written
+    /// and flushes the sinks
+    fn flush(&self) {
+        if let Some(queue) = &self.queue {
+            queue.flush();
+            self.report_dropped();
+            queue.flush();
+        }
+        self.writer.flush();
     }
 }
@@ -0,0 +1,428 @@
+//! Rate limiting and suppression of repeated messages
+//!
+//! A dependency stuck in a loop can write the same error millions of times and roll the
+//! Application log over in minutes. With [`Builder::rate_limit`](crate::Builder::rate_limit)
+//! every call site or message, see [`RateKey`], gets a token bucket per level: a burst of events
+//! is written right away, after which events are only written as fast as the bucket refills.
+//!
+//! Suppressed events aren't lost without a trace. They are counted in
+//! [`Stats::suppressed`](crate::Stats::suppressed), and collapsed into a summary: a copy of the
+//! first suppressed record with a message such as `previous message repeated 4,213 times: disk
+//! full`, which goes through the configured [format](crate::Builder::format) and
+//! [fields](crate::Builder::fields) like any other record. The summary is written before the next
+//! event the bucket lets through, and at least every [`RateLimit::summary_interval`] while
+//! events keep being suppressed. There is no timer thread, so a message that stops repeating
+//! gets its summary on a later log call, or when the logger is
+//! [shut down](crate::Logger::shutdown).
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Record};
+
+use crate::Sid;
+
+/// The most keys tracked at once, so records with unique messages can't grow the map without
+/// bound
+const MAX_ENTRIES: usize = 4096;
+
+/// What makes two records count against the same bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateKey {
+    /// Records logged from the same file and line
+    ///
+    /// Records without a location, such as those built by hand, are told apart by their target
+    /// and module path.
+    #[default]
+    CallSite,
+    /// Records with the same message, wherever they are logged from
+    ///
+    /// The message is the record's arguments, before the [format](crate::Builder::format) is
+    /// applied.
+    Message,
+}
+
+/// A token bucket, holding at most `burst` tokens and refilling `burst` tokens every `per`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    burst: f64,
+    /// Tokens per second
+    rate: f64,
+}
+
+/// Limits on how often the same record is written, per level
+///
+/// Levels without a limit are never suppressed. At most 4,096 keys are tracked at once: keys
+/// whose bucket is full again are forgotten, and once every tracked key has suppressed records,
+/// records with a new key are written without a limit until the next summary.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use log::{Level, Log};
+/// use win_service_logger::{format, Builder, RateKey, RateLimit, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Message)
+///     .rate_limit(
+///         RateLimit::new(RateKey::Message).level(Level::Error, 2, Duration::from_secs(60)),
+///     )
+///     .sink(sink.clone())
+///     .build();
+///
+/// for _ in 0..1000 {
+///     logger.log(
+///         &log::Record::builder()
+///             .args(format_args!("disk full"))
+///             .level(Level::Error)
+///             .build(),
+///     );
+/// }
+/// logger.shutdown();
+///
+/// let messages: Vec<_> = sink.events().into_iter().map(|e| e.strings[0].clone()).collect();
+/// assert_eq!(
+///     messages,
+///     ["disk full", "disk full", "previous message repeated 998 times: disk full"]
+/// );
+/// assert_eq!(logger.stats().suppressed, 998);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    key: RateKey,
+    /// Indexed by `Level as usize - 1`
+    buckets: [Option<Bucket>; 5],
+    summary_interval: Duration,
+}
+
+impl RateLimit {
+    /// Creates a rate limit which tells records apart by `key`, without limits on any level
+    pub fn new(key: RateKey) -> Self {
+        Self {
+            key,
+            buckets: [None; 5],
+            summary_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Writes at most `burst` records with the same key at `level` in a row, and after that
+    /// `burst` records every `per`
+    ///
+    /// A `burst` of 0 is treated as 1, so the first record is always written. A `per` of zero
+    /// removes the limit.
+    pub fn level(mut self, level: Level, burst: u32, per: Duration) -> Self {
+        let burst = f64::from(burst.max(1));
+        self.buckets[level as usize - 1] = match per.as_secs_f64() {
+            secs if secs > 0.0 => Some(Bucket {
+                burst,
+                rate: burst / secs,
+            }),
+            _ => None,
+        };
+        self
+    }
+
+    /// Writes a summary of the suppressed records at least this often while a record keeps
+    /// being suppressed
+    ///
+    /// Defaults to one minute.
+    pub fn summary_interval(mut self, interval: Duration) -> Self {
+        self.summary_interval = interval;
+        self
+    }
+}
+
+/// Whether a record should be written
+pub(crate) enum Verdict {
+    Write,
+    Suppress,
+}
+
+/// The state behind a [`RateLimit`], owned by the logger
+pub(crate) struct Limiter {
+    config: RateLimit,
+    state: Mutex<State>,
+}
+
+struct State {
+    entries: HashMap<u64, Entry>,
+    last_sweep: Instant,
+}
+
+struct Entry {
+    bucket: Bucket,
+    tokens: f64,
+    refilled: Instant,
+    /// Records suppressed since the last summary
+    suppressed: u64,
+    /// When the first of them was suppressed
+    since: Instant,
+    /// What the summaries are built from
+    template: Option<Template>,
+}
+
+/// The first suppressed record for a key, kept to build its summaries from
+#[derive(Debug, Clone)]
+pub(crate) struct Template {
+    level: Level,
+    target: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    message: String,
+    key_values: Vec<(String, String)>,
+    /// The SID the record was logged for, which may have come from a scope on its thread
+    user_sid: Option<Sid>,
+}
+
+impl Template {
+    pub(crate) fn new(record: &Record, user_sid: Option<Sid>) -> Self {
+        let mut message = String::new();
+        if write!(message, "{}", record.args()).is_err() {
+            message.push_str("<formatting error>");
+        }
+        let mut key_values = KeyValues(Vec::new());
+        let _ = record.key_values().visit(&mut key_values);
+        Self {
+            level: record.level(),
+            target: record.target().to_owned(),
+            module_path: record.module_path().map(str::to_owned),
+            file: record.file().map(str::to_owned),
+            line: record.line(),
+            message,
+            key_values: key_values.0,
+            user_sid,
+        }
+    }
+}
+
+struct KeyValues(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValues {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// The records suppressed for a key since its last summary
+pub(crate) struct Summary {
+    template: Template,
+    count: u64,
+}
+
+impl Summary {
+    /// Calls `f` with a copy of the template record, with the summary as its message
+    pub(crate) fn with_record<R>(&self, f: impl FnOnce(&Record) -> R) -> R {
+        let template = &self.template;
+        let key_values: Vec<_> = template
+            .key_values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let times = if self.count == 1 { "time" } else { "times" };
+        f(&Record::builder()
+            .args(format_args!(
+                "previous message repeated {} {}: {}",
+                grouped(self.count),
+                times,
+                template.message
+            ))
+            .level(template.level)
+            .target(&template.target)
+            .module_path(template.module_path.as_deref())
+            .file(template.file.as_deref())
+            .line(template.line)
+            .key_values(&key_values.as_slice())
+            .build())
+    }
+
+    /// The SID of the first suppressed record
+    pub(crate) fn user_sid(&self) -> Option<&Sid> {
+        self.template.user_sid.as_ref()
+    }
+}
+
+impl Entry {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bucket.rate).min(self.bucket.burst);
+        self.refilled = now;
+    }
+
+    /// The summary of the records suppressed so far, resetting the count
+    fn summary(&mut self, now: Instant) -> Option<Summary> {
+        let template = self.template.as_ref().filter(|_| self.suppressed > 0)?;
+        let summary = Summary {
+            template: template.clone(),
+            count: self.suppressed,
+        };
+        self.suppressed = 0;
+        self.since = now;
+        Some(summary)
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(config: RateLimit) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // Entries are updated field by field, but any mix of old and new values is usable
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Takes a token for `record`, adding the summaries that are due to `summaries`
+    ///
+    /// `template` builds the template summaries of `record` are based on, and is only called
+    /// when it is first suppressed.
+    pub(crate) fn check(
+        &self,
+        record: &Record,
+        now: Instant,
+        template: impl FnOnce() -> Template,
+        summaries: &mut Vec<Summary>,
+    ) -> Verdict {
+        let bucket = self.config.buckets[record.level() as usize - 1];
+        let key = bucket.map(|_| self.key(record));
+        let (verdict, untemplated) = {
+            let mut state = self.lock();
+            let taken = match (bucket, key) {
+                (Some(bucket), Some(key)) => self.take(&mut state, key, bucket, now, summaries),
+                _ => (Verdict::Write, None),
+            };
+            // Records that stopped repeating are summarized whatever level is logged next
+            self.sweep(&mut state, now, summaries);
+            taken
+        };
+        if let Some(key) = untemplated {
+            // Built without the lock, the record's arguments may log themselves
+            let template = template();
+            if let Some(entry) = self.lock().entries.get_mut(&key) {
+                entry.template.get_or_insert(template);
+            }
+        }
+        verdict
+    }
+
+    /// Takes a token from the bucket of `key`, returning the key if its template is missing
+    fn take(
+        &self,
+        state: &mut State,
+        key: u64,
+        bucket: Bucket,
+        now: Instant,
+        summaries: &mut Vec<Summary>,
+    ) -> (Verdict, Option<u64>) {
+        if state.entries.len() >= MAX_ENTRIES && !state.entries.contains_key(&key) {
+            // Keys without suppressed records only lose the tokens they used
+            state.entries.retain(|_, entry| entry.suppressed > 0);
+            if state.entries.len() >= MAX_ENTRIES {
+                return (Verdict::Write, None);
+            }
+        }
+        let entry = state.entries.entry(key).or_insert_with(|| Entry {
+            bucket,
+            tokens: bucket.burst,
+            refilled: now,
+            suppressed: 0,
+            since: now,
+            template: None,
+        });
+        entry.refill(now);
+        if entry.tokens >= 1.0 {
+            entry.tokens -= 1.0;
+            summaries.extend(entry.summary(now));
+            return (Verdict::Write, None);
+        }
+        if entry.suppressed == 0 {
+            entry.since = now;
+        }
+        entry.suppressed += 1;
+        if now.saturating_duration_since(entry.since) >= self.config.summary_interval {
+            summaries.extend(entry.summary(now));
+        }
+        (Verdict::Suppress, entry.template.is_none().then_some(key))
+    }
+
+    /// Adds the summaries of every record still being suppressed to `summaries`
+    pub(crate) fn drain(&self, summaries: &mut Vec<Summary>) {
+        let now = Instant::now();
+        let mut state = self.lock();
+        summaries.extend(state.entries.values_mut().filter_map(|e| e.summary(now)));
+    }
+
+    /// Once per summary interval, summarizes records which were suppressed and then stopped
+    /// repeating, and forgets the ones with a full bucket
+    fn sweep(&self, state: &mut State, now: Instant, summaries: &mut Vec<Summary>) {
+        let interval = self.config.summary_interval;
+        if now.saturating_duration_since(state.last_sweep) < interval {
+            return;
+        }
+        state.last_sweep = now;
+        state.entries.retain(|_, entry| {
+            entry.refill(now);
+            if entry.suppressed > 0 && now.saturating_duration_since(entry.since) >= interval {
+                summaries.extend(entry.summary(now));
+            }
+            entry.suppressed > 0 || entry.tokens < entry.bucket.burst
+        });
+    }
+
+    fn key(&self, record: &Record) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        record.level().hash(&mut hasher);
+        match self.config.key {
+            RateKey::CallSite => {
+                record.file().hash(&mut hasher);
+                record.line().hash(&mut hasher);
+                if record.file().is_none() {
+                    record.target().hash(&mut hasher);
+                    record.module_path().hash(&mut hasher);
+                }
+            }
+            RateKey::Message => {
+                // Hash the message as it is formatted, without collecting it into a string
+                let _ = write!(HashWriter(&mut hasher), "{}", record.args());
+            }
+        }
+        hasher.finish()
+    }
+}
+
+struct HashWriter<'a>(&'a mut DefaultHasher);
+
+impl Write for HashWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// `n` with thousands separators, such as `4,213`
+fn grouped(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
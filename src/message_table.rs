@@ -0,0 +1,302 @@
+//! Generating a message table resource from a build script
+//!
+//! Event Viewer looks up the description of an event in the message table of the file
+//! registered as the `EventMessageFile` of its source. Without one every event is shown with
+//! "The description for Event ID ... cannot be found".
+//!
+//! [`MessageTable`] takes a list of categories and messages and generates three files from it:
+//!
+//! - `<name>.mc`, the input for the message compiler `mc.exe`
+//! - `<name>.rc`, a resource script which embeds the compiled message table
+//! - `<name>.rs`, a Rust module with a constant for every category and event ID
+//!
+//! Including the generated module in the crate keeps the IDs passed to [`Builder::event_id`]
+//! and the `event_id` key-value in sync with the resource.
+//!
+//! [`Builder::event_id`]: crate::Builder::event_id
+//!
+//! # Example
+//!
+//! In `build.rs`:
+//!
+//! ```no_run
+//! use win_service_logger::message_table::MessageTable;
+//!
+//! fn main() {
+//!     let out_dir = std::env::var("OUT_DIR").unwrap();
+//!     MessageTable::new()
+//!         .category("CATEGORY_DATABASE", "Database")
+//!         .category("CATEGORY_NETWORK", "Network")
+//!         .message(1000, "MSG_STARTED", "The service started")
+//!         .message(1001, "MSG_DISK_FULL", "The disk %1 is full")
+//!         .write_files(&out_dir, "messages")
+//!         .unwrap();
+//!
+//!     // Compile messages.mc with `mc.exe` and messages.rc with `rc.exe`, for example with
+//!     // the `embed-resource` crate, to link the message table into the binary.
+//! }
+//! ```
+//!
+//! And in the crate:
+//!
+//! ```ignore
+//! mod messages {
+//!     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+//! }
+//!
+//! log::error!(event_id = messages::MSG_DISK_FULL; "C:");
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::io;
+use std::path::Path;
+
+/// A list of categories and messages to generate a message table from
+#[derive(Debug, Clone, Default)]
+pub struct MessageTable {
+    categories: Vec<Entry>,
+    messages: Vec<(u32, Entry)>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    symbolic_name: String,
+    text: String,
+}
+
+/// The contents of the generated files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedFiles {
+    /// Message compiler input
+    pub mc: String,
+    /// Resource script
+    pub rc: String,
+    /// Rust constants
+    pub rs: String,
+}
+
+/// The largest event ID a message can have, `mc.exe` only accepts 16-bit message IDs
+pub const MAX_MESSAGE_ID: u32 = 0xFFFF;
+
+/// The reason a [`MessageTable`] couldn't be generated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageTableError {
+    /// Two messages share an event ID
+    DuplicateId(u32),
+    /// A message uses an event ID in the range reserved for categories, `1..=category count`
+    IdConflictsWithCategory(u32),
+    /// A message uses an event ID above [`MAX_MESSAGE_ID`], which `mc.exe` rejects
+    IdTooLarge(u32),
+    /// Two entries share a symbolic name
+    DuplicateName(String),
+    /// A symbolic name isn't a valid identifier
+    InvalidName(String),
+}
+
+impl fmt::Display for MessageTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageTableError::DuplicateId(id) => write!(f, "event ID {} is used twice", id),
+            MessageTableError::IdConflictsWithCategory(id) => {
+                write!(f, "event ID {} is reserved for a category", id)
+            }
+            MessageTableError::IdTooLarge(id) => {
+                write!(f, "event ID {} is larger than {}", id, MAX_MESSAGE_ID)
+            }
+            MessageTableError::DuplicateName(name) => {
+                write!(f, "symbolic name {} is used twice", name)
+            }
+            MessageTableError::InvalidName(name) => {
+                write!(f, "symbolic name {:?} is not a valid identifier", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageTableError {}
+
+impl MessageTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a category shown as `text` in Event Viewer
+    ///
+    /// Categories are numbered from 1 in the order they are added, which is also the number
+    /// the generated constant `symbolic_name` has.
+    pub fn category(mut self, symbolic_name: impl Into<String>, text: impl Into<String>) -> Self {
+        self.categories.push(Entry {
+            symbolic_name: symbolic_name.into(),
+            text: text.into(),
+        });
+        self
+    }
+
+    /// Adds the message template `text` for `event_id`
+    ///
+    /// `%1`, `%2`, ... in `text` are replaced by the insertion strings of an event. `event_id`
+    /// can be at most [`MAX_MESSAGE_ID`].
+    pub fn message(
+        mut self,
+        event_id: u32,
+        symbolic_name: impl Into<String>,
+        text: impl Into<String>,
+    ) -> Self {
+        self.messages.push((
+            event_id,
+            Entry {
+                symbolic_name: symbolic_name.into(),
+                text: text.into(),
+            },
+        ));
+        self
+    }
+
+    /// The number of categories, to be registered as the `CategoryCount` of the event source
+    pub fn category_count(&self) -> u32 {
+        self.categories.len() as u32
+    }
+
+    /// Generates the contents of the `.mc`, `.rc` and `.rs` files
+    ///
+    /// # Errors
+    ///
+    /// Fails if IDs or symbolic names are used twice, a symbolic name isn't a valid identifier,
+    /// or a message ID overlaps with the category IDs or is above [`MAX_MESSAGE_ID`]
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use win_service_logger::message_table::{MessageTable, MessageTableError};
+    ///
+    /// let files = MessageTable::new()
+    ///     .category("CATEGORY_DATABASE", "Database")
+    ///     .message(1001, "MSG_DISK_FULL", "The disk %1 is full")
+    ///     .generate()
+    ///     .unwrap();
+    ///
+    /// assert!(files.mc.contains("MessageId=1001\nSymbolicName=MSG_DISK_FULL\n"));
+    /// assert!(files.rs.contains("pub const CATEGORY_DATABASE: u16 = 1;"));
+    /// assert!(files.rs.contains("pub const MSG_DISK_FULL: u32 = 1001;"));
+    ///
+    /// let err = MessageTable::new()
+    ///     .message(0x1_0000, "MSG_TOO_LARGE", "Too large")
+    ///     .generate()
+    ///     .unwrap_err();
+    /// assert_eq!(err, MessageTableError::IdTooLarge(0x1_0000));
+    /// ```
+    pub fn generate(&self) -> Result<GeneratedFiles, MessageTableError> {
+        self.validate()?;
+        Ok(GeneratedFiles {
+            mc: self.mc(),
+            rc: String::from(RC),
+            rs: self.rs(),
+        })
+    }
+
+    /// Generates the files and writes them to `<dir>/<name>.mc`, `<dir>/<name>.rc` and
+    /// `<dir>/<name>.rs`
+    ///
+    /// # Errors
+    ///
+    /// Fails if the table is invalid, see [`MessageTable::generate`], or if a file can't be
+    /// written
+    pub fn write_files(&self, dir: impl AsRef<Path>, name: &str) -> io::Result<()> {
+        let files = self
+            .generate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let dir = dir.as_ref();
+        std::fs::write(dir.join(format!("{}.mc", name)), files.mc)?;
+        std::fs::write(dir.join(format!("{}.rc", name)), files.rc)?;
+        std::fs::write(dir.join(format!("{}.rs", name)), files.rs)
+    }
+
+    fn validate(&self) -> Result<(), MessageTableError> {
+        let mut names = HashSet::new();
+        let all = self
+            .categories
+            .iter()
+            .chain(self.messages.iter().map(|(_, e)| e));
+        for entry in all {
+            if !is_identifier(&entry.symbolic_name) {
+                return Err(MessageTableError::InvalidName(entry.symbolic_name.clone()));
+            }
+            if !names.insert(entry.symbolic_name.as_str()) {
+                return Err(MessageTableError::DuplicateName(
+                    entry.symbolic_name.clone(),
+                ));
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for (id, _) in &self.messages {
+            if *id > MAX_MESSAGE_ID {
+                return Err(MessageTableError::IdTooLarge(*id));
+            }
+            if (1..=self.category_count()).contains(id) {
+                return Err(MessageTableError::IdConflictsWithCategory(*id));
+            }
+            if !ids.insert(*id) {
+                return Err(MessageTableError::DuplicateId(*id));
+            }
+        }
+        Ok(())
+    }
+
+    fn mc(&self) -> String {
+        let mut mc = String::from("; Generated by win-service-logger, do not edit\n\n");
+        mc.push_str("MessageIdTypedef=DWORD\n\n");
+        mc.push_str("LanguageNames=(English=0x409:MSG00409)\n");
+
+        let categories = (1..).zip(&self.categories);
+        for (id, entry) in categories.chain(self.messages.iter().map(|(id, e)| (*id, e))) {
+            // Writing to a String can't fail
+            let _ = write!(
+                mc,
+                "\nMessageId={}\nSymbolicName={}\nLanguage=English\n",
+                id, entry.symbolic_name
+            );
+            for line in entry.text.lines() {
+                // A line holding a single period ends the message, `%.` escapes it
+                mc.push_str(if line == "." { "%." } else { line });
+                mc.push('\n');
+            }
+            mc.push_str(".\n");
+        }
+        mc
+    }
+
+    fn rs(&self) -> String {
+        let mut rs = String::from("// Generated by win-service-logger, do not edit\n");
+        if !self.categories.is_empty() {
+            rs.push('\n');
+        }
+        for (id, entry) in (1..).zip(&self.categories) {
+            let _ = writeln!(rs, "/// {}", entry.text.lines().next().unwrap_or(""));
+            let _ = writeln!(rs, "pub const {}: u16 = {};", entry.symbolic_name, id);
+        }
+        if !self.messages.is_empty() {
+            rs.push('\n');
+        }
+        for (id, entry) in &self.messages {
+            let _ = writeln!(rs, "/// {}", entry.text.lines().next().unwrap_or(""));
+            let _ = writeln!(rs, "pub const {}: u32 = {};", entry.symbolic_name, id);
+        }
+        rs
+    }
+}
+
+/// The resource script `mc.exe` generates for a table in English, which embeds the compiled
+/// `MSG00409.bin` as resource 1 of type `RT_MESSAGETABLE` (11)
+const RC: &str = "LANGUAGE 0x9,0x1\n1 11 \"MSG00409.bin\"\n";
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
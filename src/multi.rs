@@ -0,0 +1,149 @@
+//! Sending records to more than one logger
+//!
+//! The `log` crate has a single global logger. [`MultiLogger`] is that logger when records
+//! should go to the event log and to other backends, such as a file logger, at the same time.
+
+use std::any::Any;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::{Logger, ShutdownHook, GLOBAL};
+
+/// Passes every record on to a list of loggers, each with its own level
+///
+/// # Example
+///
+/// ```
+/// use log::{LevelFilter, Log};
+/// use win_service_logger::{Logger, MultiLogger, RecordingSink};
+///
+/// let event_log = RecordingSink::new();
+/// let file = RecordingSink::new();
+/// let logger = MultiLogger::new()
+///     .logger(Logger::new(event_log.clone()), LevelFilter::Warn)
+///     .logger(Logger::new(file.clone()), LevelFilter::Trace);
+///
+/// for level in [log::Level::Error, log::Level::Debug] {
+///     logger.log(
+///         &log::Record::builder()
+///             .args(format_args!("cache miss"))
+///             .level(level)
+///             .build(),
+///     );
+/// }
+///
+/// assert_eq!(event_log.events().len(), 1);
+/// assert_eq!(file.events().len(), 2);
+/// assert_eq!(logger.max_level(), LevelFilter::Trace);
+/// ```
+#[derive(Default)]
+pub struct MultiLogger {
+    loggers: Vec<(Box<dyn AnyLog>, LevelFilter)>,
+}
+
+/// A [`Log`] which can be downcast, to find the [`Logger`]s to shut down
+trait AnyLog: Log {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<L: Log + Any> AnyLog for L {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl MultiLogger {
+    /// Creates a logger which passes records on to nothing yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Passes records up to `level` on to `logger`
+    ///
+    /// `logger` still applies its own filters on top of `level`.
+    pub fn logger(mut self, logger: impl Log + 'static, level: LevelFilter) -> Self {
+        self.loggers.push((Box::new(logger), level));
+        self
+    }
+
+    /// Returns the most verbose level any of the loggers takes
+    pub fn max_level(&self) -> LevelFilter {
+        self.loggers
+            .iter()
+            .map(|(_, level)| *level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+
+    /// Installs this logger as the global logger and sets [`log::max_level`] to
+    /// [`MultiLogger::max_level`]
+    ///
+    /// This function leaks the `MultiLogger` to the heap in order to give a static reference to
+    /// log
+    ///
+    /// # Errors
+    ///
+    /// This function fails if a global logger has already been set
+    pub fn try_init(self) -> Result<(), log::SetLoggerError> {
+        let logger = Box::leak(Box::new(self));
+        log::set_logger(logger)?;
+        log::set_max_level(logger.max_level());
+        // `set_logger` succeeds only once, so this is the first and only global logger
+        let _ = GLOBAL.set(logger);
+        Ok(())
+    }
+
+    /// Shuts down every [`Logger`] this passes records on to, including those in nested
+    /// `MultiLogger`s, see [`Logger::shutdown`]
+    ///
+    /// Other loggers are flushed and keep taking records.
+    pub fn shutdown(&self) {
+        for (logger, _) in &self.loggers {
+            let any = logger.as_any();
+            if let Some(logger) = any.downcast_ref::<Logger>() {
+                logger.shutdown();
+            } else if let Some(multi) = any.downcast_ref::<MultiLogger>() {
+                multi.shutdown();
+            } else {
+                logger.flush();
+            }
+        }
+    }
+
+    /// Installs this logger as the global logger
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if a global logger has already been set
+    pub fn init(self) {
+        self.try_init().unwrap();
+    }
+}
+
+impl ShutdownHook for MultiLogger {
+    fn shutdown(&self) {
+        MultiLogger::shutdown(self);
+    }
+}
+
+impl Log for MultiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.loggers
+            .iter()
+            .any(|(logger, level)| metadata.level() <= *level && logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for (logger, level) in &self.loggers {
+            if record.level() <= *level && logger.enabled(record.metadata()) {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for (logger, _) in &self.loggers {
+            logger.flush();
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Handling NUL characters in messages
+//!
+//! The event log takes NUL terminated strings, so a NUL inside a message would cut it short.
+//! [`NulPolicy`] decides what the logger writes in its place.
+
+/// What to write in place of the NUL characters in a message
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{format, Builder, NulPolicy, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Message)
+///     .nul_policy(NulPolicy::Replace('?'))
+///     .sink(sink.clone())
+///     .build();
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("read {:?}", "a\0b"))
+///         .level(log::Level::Info)
+///         .build(),
+/// );
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("read a\0b"))
+///         .level(log::Level::Info)
+///         .build(),
+/// );
+///
+/// let events = sink.events();
+/// assert_eq!(events[0].strings[0], r#"read "a\0b""#);
+/// assert_eq!(events[1].strings[0], "read a?b");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NulPolicy {
+    /// Write `\0`, the default
+    #[default]
+    Escape,
+    /// Write the given character, or nothing if it is NUL itself
+    Replace(char),
+    /// Leave NULs out
+    Strip,
+}
+
+impl NulPolicy {
+    /// Applies the policy to `s` in place
+    pub fn apply(self, s: &mut String) {
+        if !s.contains('\0') {
+            return;
+        }
+        *s = match self {
+            NulPolicy::Escape => s.replace('\0', "\\0"),
+            NulPolicy::Replace(c) if c != '\0' => s.replace('\0', c.encode_utf8(&mut [0; 4])),
+            NulPolicy::Replace(_) | NulPolicy::Strip => s.replace('\0', ""),
+        };
+    }
+}
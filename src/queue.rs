@@ -0,0 +1,235 @@
+//! Writing events on a background thread
+//!
+//! `ReportEventW` is a synchronous call into the EventLog service, which can take a while when
+//! the system is busy. With [`Builder::async_queue`](crate::Builder::async_queue) the logging
+//! thread only builds the event and puts it in a bounded queue, and a background thread writes
+//! it to the sinks. [`Overflow`] decides what happens when the queue is full, and
+//! [`Log::flush`](log::Log::flush) waits until the queue is empty.
+
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread::{JoinHandle, ThreadId};
+
+use crate::writer::Writer;
+use crate::Event;
+
+const THREAD_NAME: &str = "event-log-writer";
+
+/// What to do with an event when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait until the background thread makes room
+    #[default]
+    Block,
+    /// Drop the new event
+    DropNewest,
+    /// Drop the oldest event in the queue to make room for the new one
+    DropOldest,
+    /// Drop the new event, and write a warning with the number of dropped events once there is
+    /// room again
+    ///
+    /// The warning is built like any other record, at [`Level::Warn`](log::Level::Warn), and
+    /// queued by the next log call, flush or shutdown that finds room in the queue.
+    Summarize,
+}
+
+/// The sending side of the queue, owned by the logger
+pub(crate) struct Queue {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when an event is queued, or the queue is closed
+    queued: Condvar,
+    /// Signalled when the background thread takes an event out of the queue
+    taken: Condvar,
+    /// Signalled when the queue is empty and the background thread is idle
+    drained: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+    writer: Arc<Writer>,
+    /// The background thread, set by whichever of it and `spawn` gets there first
+    thread: OnceLock<ThreadId>,
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<Event>,
+    /// Events dropped under [`Overflow::Summarize`] since the last summary was taken
+    unreported: u64,
+    /// Whether the background thread is writing an event right now
+    busy: bool,
+    closed: bool,
+}
+
+impl State {
+    fn idle(&self) -> bool {
+        self.events.is_empty() && !self.busy
+    }
+}
+
+impl Queue {
+    /// Starts the background thread, which writes the queued events with `writer`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the thread can't be started
+    pub(crate) fn spawn(
+        capacity: usize,
+        overflow: Overflow,
+        writer: Arc<Writer>,
+    ) -> std::io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            queued: Condvar::new(),
+            taken: Condvar::new(),
+            drained: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+            writer,
+            thread: OnceLock::new(),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new()
+                .name(THREAD_NAME.into())
+                .spawn(move || shared.run())?
+        };
+        let _ = shared.thread.set(thread.thread().id());
+        Ok(Self {
+            shared,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Queues `event`, applying the overflow policy if the queue is full
+    pub(crate) fn push(&self, event: Event) {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        if state.closed {
+            shared.writer.stats.drop_event();
+            return;
+        }
+        if state.events.len() >= shared.capacity {
+            match shared.overflow {
+                Overflow::Block => {
+                    while state.events.len() >= shared.capacity && !state.closed {
+                        state = shared.taken.wait(state).unwrap_or_else(|e| e.into_inner());
+                    }
+                    // The background thread may have exited already
+                    if state.closed {
+                        shared.writer.stats.drop_event();
+                        return;
+                    }
+                }
+                Overflow::DropNewest => {
+                    shared.writer.stats.drop_event();
+                    return;
+                }
+                Overflow::DropOldest => {
+                    state.events.pop_front();
+                    shared.writer.stats.drop_event();
+                }
+                Overflow::Summarize => {
+                    state.unreported += 1;
+                    shared.writer.stats.drop_event();
+                    return;
+                }
+            }
+        }
+        state.events.push_back(event);
+        shared.queued.notify_one();
+    }
+
+    /// Returns the number of events dropped under [`Overflow::Summarize`] since the last call,
+    /// or 0 if the queue is still full
+    pub(crate) fn take_unreported(&self) -> u64 {
+        let mut state = self.shared.lock();
+        if state.events.len() < self.shared.capacity {
+            std::mem::take(&mut state.unreported)
+        } else {
+            0
+        }
+    }
+
+    /// Waits until every queued event has been written
+    pub(crate) fn flush(&self) {
+        let shared = &*self.shared;
+        if shared.thread.get() == Some(&std::thread::current().id()) {
+            // A sink logging from the background thread would wait for itself
+            return;
+        }
+        let mut state = shared.lock();
+        while !state.idle() && !state.closed {
+            state = shared
+                .drained
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Writes the events still in the queue and stops the background thread
+    ///
+    /// Events queued afterwards are dropped.
+    pub(crate) fn close(&self) {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_one();
+        self.shared.taken.notify_all();
+        let thread = self.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(thread) = thread {
+            // The background thread may be the one closing the queue, from a sink that logs
+            if thread.thread().id() != std::thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The queue is only changed with single calls that can't panic halfway
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes `event`, keeping the background thread alive if a sink panics
+    fn write(&self, event: &Event) {
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| self.writer.write(event)));
+    }
+
+    /// The loop of the background thread
+    fn run(&self) {
+        let _ = self.thread.set(std::thread::current().id());
+        let mut state = self.lock();
+        loop {
+            while state.events.is_empty() && !state.closed {
+                state = self.queued.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            let Some(event) = state.events.pop_front() else {
+                // Closed and nothing left to write
+                break;
+            };
+            state.busy = true;
+            self.taken.notify_all();
+            drop(state);
+
+            self.write(&event);
+
+            state = self.lock();
+            state.busy = false;
+            if state.idle() {
+                self.drained.notify_all();
+            }
+        }
+        state.busy = false;
+        self.drained.notify_all();
+    }
+}
@@ -0,0 +1,337 @@
+//! Filter configuration stored in the registry
+//!
+//! Windows services are usually configured through the registry, so the level and filter
+//! directives of a [`Logger`](crate::Logger) can be read from two string values under a key:
+//!
+//! - [`LEVEL_VALUE`] holds a default level such as `info`, one of `off`, `error`, `warn`,
+//!   `info`, `debug` or `trace`
+//! - [`FILTERS_VALUE`] holds `env_logger` style directives such as `info,hyper=warn`
+//!
+//! Registry access goes through the [`KeyStore`] trait. On Windows [`RegistryKey`] reads a real
+//! key, and [`MemoryKeyStore`] can be used to test configuration handling on any platform.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+
+use log::LevelFilter;
+
+use crate::filter::{Filter, FilterState};
+
+/// Name of the string value holding the default level
+pub const LEVEL_VALUE: &str = "LogLevel";
+
+/// Name of the string value holding filter directives
+pub const FILTERS_VALUE: &str = "LogFilters";
+
+/// A registry key holding configuration values
+pub trait KeyStore: Send + Sync {
+    /// Reads the string value `name`, returning `None` if it doesn't exist
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be read or the value isn't a string
+    fn get_string(&self, name: &str) -> io::Result<Option<String>>;
+
+    /// Blocks until a value in the key changes
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key can't be watched
+    fn wait_for_change(&self) -> io::Result<()>;
+}
+
+impl<K: KeyStore + ?Sized> KeyStore for Arc<K> {
+    fn get_string(&self, name: &str) -> io::Result<Option<String>> {
+        (**self).get_string(name)
+    }
+
+    fn wait_for_change(&self) -> io::Result<()> {
+        (**self).wait_for_change()
+    }
+}
+
+/// An in-memory [`KeyStore`]
+///
+/// Clones share the same values, so a clone can be given to a [`Logger`](crate::Logger) while
+/// the original is used to change its configuration.
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::registry::{MemoryKeyStore, FILTERS_VALUE};
+/// use win_service_logger::{Builder, RecordingSink};
+///
+/// let store = MemoryKeyStore::new();
+/// store.set(FILTERS_VALUE, "warn");
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .registry(store.clone())
+///     .sink(sink.clone())
+///     .build();
+/// let record = log::Record::builder()
+///     .args(format_args!("started"))
+///     .level(log::Level::Info)
+///     .build();
+///
+/// logger.log(&record);
+/// assert!(sink.events().is_empty());
+///
+/// store.set(FILTERS_VALUE, "info");
+/// logger.reload().unwrap();
+/// logger.log(&record);
+/// assert_eq!(sink.events().len(), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKeyStore {
+    inner: Arc<(Mutex<MemoryValues>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryValues {
+    values: HashMap<String, String>,
+    /// Incremented on every change so that waiters can tell when to wake up
+    generation: u64,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the string value `name`, waking up anyone waiting for a change
+    pub fn set(&self, name: impl Into<String>, value: impl Into<String>) {
+        self.lock().values.insert(name.into(), value.into());
+        self.changed();
+    }
+
+    /// Removes the value `name`, waking up anyone waiting for a change
+    pub fn remove(&self, name: &str) {
+        self.lock().values.remove(name);
+        self.changed();
+    }
+
+    fn changed(&self) {
+        self.lock().generation += 1;
+        self.inner.1.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryValues> {
+        self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn get_string(&self, name: &str) -> io::Result<Option<String>> {
+        Ok(self.lock().values.get(name).cloned())
+    }
+
+    fn wait_for_change(&self) -> io::Result<()> {
+        let values = self.lock();
+        let generation = values.generation;
+        let _values = self
+            .inner
+            .1
+            .wait_while(values, |values| values.generation == generation)
+            .unwrap_or_else(|e| e.into_inner());
+        Ok(())
+    }
+}
+
+/// Applies the configuration in a [`KeyStore`] on top of the levels a logger was built with
+#[derive(Clone)]
+pub(crate) struct RegistryConfig {
+    store: Arc<dyn KeyStore>,
+    /// The levels configured in code, which registry values are layered on top of
+    base: FilterState,
+}
+
+impl RegistryConfig {
+    pub(crate) fn new(store: Arc<dyn KeyStore>, filter: &Filter) -> Self {
+        Self {
+            store,
+            base: filter.state(),
+        }
+    }
+
+    /// Re-reads the configuration and applies it to `filter`
+    ///
+    /// `filter` is only changed if every value could be read and parsed. An invalid level, or
+    /// invalid directives, are returned as an [`io::ErrorKind::InvalidData`] error.
+    pub(crate) fn apply(&self, filter: &Filter) -> io::Result<()> {
+        let level = match self.store.get_string(LEVEL_VALUE)? {
+            Some(level) => Some(parse_level(&level)?),
+            None => None,
+        };
+        let directives = match self.store.get_string(FILTERS_VALUE)? {
+            Some(filters) => crate::filter::parse_directives(&filters)?,
+            None => Vec::new(),
+        };
+
+        filter.restore(&self.base);
+        if let Some(level) = level {
+            filter.set_level(level);
+        }
+        for directive in directives {
+            filter.apply(directive);
+        }
+        Ok(())
+    }
+
+    /// Starts a thread which applies the configuration to `filter` whenever it changes
+    ///
+    /// The thread exits after the first change once `filter` has been dropped, or if the key
+    /// can no longer be watched.
+    pub(crate) fn watch(self, filter: Weak<Filter>) -> io::Result<()> {
+        thread::Builder::new()
+            .name(String::from("win-service-logger registry watcher"))
+            .spawn(move || {
+                while self.store.wait_for_change().is_ok() {
+                    match filter.upgrade() {
+                        Some(filter) => {
+                            let _ = self.apply(&filter);
+                        }
+                        None => break,
+                    }
+                }
+            })
+            .map(|_| ())
+    }
+}
+
+/// Parses the value of [`LEVEL_VALUE`], which has to be a level such as `warn`
+fn parse_level(level: &str) -> io::Result<LevelFilter> {
+    level.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "invalid value '{}' for {}, expected a level",
+                level, LEVEL_VALUE
+            ),
+        )
+    })
+}
+
+#[cfg(windows)]
+pub use self::win32::RegistryKey;
+
+#[cfg(windows)]
+mod win32 {
+    use std::io;
+
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::shared::winerror::ERROR_FILE_NOT_FOUND;
+    use winapi::um::winnt::{KEY_NOTIFY, KEY_READ, REG_NOTIFY_CHANGE_LAST_SET, REG_SZ};
+    use winapi::um::winreg::{RegNotifyChangeKeyValue, RegQueryValueExW};
+
+    use super::KeyStore;
+    use crate::regkey::{check, wide, Key};
+
+    /// A key under `HKEY_LOCAL_MACHINE`
+    pub struct RegistryKey {
+        key: Key,
+    }
+
+    impl RegistryKey {
+        /// Opens `HKLM\<path>` for reading and watching
+        ///
+        /// # Errors
+        ///
+        /// Returns an error if the key doesn't exist or can't be opened
+        pub fn open(path: &str) -> io::Result<Self> {
+            let key = Key::open(path, KEY_READ | KEY_NOTIFY)?;
+            Ok(Self { key })
+        }
+
+        /// Opens the key of the event source `source` in the event log `log`
+        ///
+        /// This is `HKLM\SYSTEM\CurrentControlSet\Services\EventLog\<log>\<source>`
+        ///
+        /// # Errors
+        ///
+        /// Returns an error if the source isn't registered
+        pub fn for_source(log: &str, source: &str) -> io::Result<Self> {
+            Self::open(&format!(
+                r"{}\{}\{}",
+                crate::install::EVENT_LOG_KEY,
+                log,
+                source
+            ))
+        }
+    }
+
+    impl KeyStore for RegistryKey {
+        fn get_string(&self, name: &str) -> io::Result<Option<String>> {
+            let name = wide(name)?;
+            let mut kind: DWORD = 0;
+            let mut size: DWORD = 0;
+            // # Safety:
+            // 1. `name` is a valid null terminated utf-16 string
+            // 2. A null data pointer asks only for the type and size of the value
+            // 3. WinAPI call
+            let status = unsafe {
+                RegQueryValueExW(
+                    self.key.raw(),
+                    name.as_ptr(),
+                    std::ptr::null_mut(),
+                    &mut kind,
+                    std::ptr::null_mut(),
+                    &mut size,
+                )
+            };
+            if status as DWORD == ERROR_FILE_NOT_FOUND {
+                return Ok(None);
+            }
+            check(status)?;
+            if kind != REG_SZ {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "registry value is not a string",
+                ));
+            }
+
+            let mut data = vec![0u16; (size as usize).div_ceil(2)];
+            // # Safety:
+            // 1. `data` has room for `size` bytes
+            // 2. WinAPI call
+            let status = unsafe {
+                RegQueryValueExW(
+                    self.key.raw(),
+                    name.as_ptr(),
+                    std::ptr::null_mut(),
+                    &mut kind,
+                    data.as_mut_ptr() as *mut u8,
+                    &mut size,
+                )
+            };
+            check(status)?;
+            data.truncate(size as usize / 2);
+            // REG_SZ values usually, but not always, include their terminating null
+            while data.last() == Some(&0) {
+                data.pop();
+            }
+            Ok(Some(String::from_utf16_lossy(&data)))
+        }
+
+        fn wait_for_change(&self) -> io::Result<()> {
+            // # Safety:
+            // 1. `self.key` was opened with KEY_NOTIFY
+            // 2. Without an event and with fAsynchronous set to FALSE the call blocks until a
+            //    value changes
+            // 3. WinAPI call
+            let status = unsafe {
+                RegNotifyChangeKeyValue(
+                    self.key.raw(),
+                    FALSE,
+                    REG_NOTIFY_CHANGE_LAST_SET,
+                    std::ptr::null_mut(),
+                    FALSE,
+                )
+            };
+            check(status)
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Keys under `HKEY_LOCAL_MACHINE`, shared by the `install` and `registry` modules
+
+use std::io;
+
+use widestring::U16CString;
+use winapi::shared::minwindef::{DWORD, HKEY};
+use winapi::shared::winerror::ERROR_SUCCESS;
+use winapi::um::winnt::REG_OPTION_NON_VOLATILE;
+use winapi::um::winreg::{
+    RegCloseKey, RegCreateKeyExW, RegOpenKeyExW, RegSetValueExW, HKEY_LOCAL_MACHINE,
+};
+
+/// An open registry key which is closed on drop
+pub(crate) struct Key(HKEY);
+
+// # Safety:
+// A registry handle isn't tied to the thread that opened it
+unsafe impl Send for Key {}
+unsafe impl Sync for Key {}
+
+impl Key {
+    /// Opens the existing key `HKLM\<path>`
+    pub(crate) fn open(path: &str, access: DWORD) -> io::Result<Self> {
+        let path = wide(path)?;
+        let mut key = std::ptr::null_mut();
+        // # Safety:
+        // 1. `path` is a valid null terminated utf-16 string
+        // 2. `key` is a valid place to write the opened key to
+        // 3. WinAPI call
+        check(unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, path.as_ptr(), 0, access, &mut key) })?;
+        Ok(Self(key))
+    }
+
+    /// Opens the key `HKLM\<path>`, creating it and any missing parent keys
+    pub(crate) fn create(path: &str, access: DWORD) -> io::Result<Self> {
+        let path = wide(path)?;
+        let mut key = std::ptr::null_mut();
+        // # Safety:
+        // 1. `path` is a valid null terminated utf-16 string
+        // 2. `key` is a valid place to write the created key to
+        // 3. WinAPI call
+        check(unsafe {
+            RegCreateKeyExW(
+                HKEY_LOCAL_MACHINE,
+                path.as_ptr(),
+                0,
+                std::ptr::null_mut(),
+                REG_OPTION_NON_VOLATILE,
+                access,
+                std::ptr::null_mut(),
+                &mut key,
+                std::ptr::null_mut(),
+            )
+        })?;
+        Ok(Self(key))
+    }
+
+    pub(crate) fn raw(&self) -> HKEY {
+        self.0
+    }
+
+    /// Sets the value `name` to `data`, which is in the format of the registry type `kind`
+    pub(crate) fn set_value(&self, name: &str, kind: DWORD, data: &[u8]) -> io::Result<()> {
+        let name = wide(name)?;
+        // # Safety:
+        // 1. `name` is a valid null terminated utf-16 string
+        // 2. `data` holds `data.len()` bytes in the format of `kind`
+        // 3. WinAPI call
+        check(unsafe {
+            RegSetValueExW(
+                self.0,
+                name.as_ptr(),
+                0,
+                kind,
+                data.as_ptr(),
+                data.len() as DWORD,
+            )
+        })
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        // # Safety:
+        // WinAPI call
+        let _ = unsafe { RegCloseKey(self.0) };
+    }
+}
+
+pub(crate) fn wide(s: &str) -> io::Result<U16CString> {
+    U16CString::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Turns the status returned by a registry function into a result
+pub(crate) fn check(status: i32) -> io::Result<()> {
+    if status as DWORD == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(status))
+    }
+}
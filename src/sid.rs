@@ -0,0 +1,310 @@
+//! Security identifiers attached to events
+//!
+//! Event Viewer shows the "User" of an event from the SID passed to `ReportEventW`. A
+//! [`Logger`](crate::Logger) picks the SID of a record from, in order:
+//!
+//! 1. the [`USER_SID_KEY`] key-value of the record, such as `user_sid = "S-1-5-18"`
+//! 2. the innermost [`UserSidScope`] on the logging thread
+//! 3. the default set with [`Builder::user_sid`](crate::Builder::user_sid)
+//!
+//! Records without a SID are shown with the user "N/A".
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::str::FromStr;
+
+use log::kv::Key;
+use log::Record;
+
+/// Name of the key-value that sets the user SID of a record
+pub const USER_SID_KEY: &str = "user_sid";
+
+/// The largest number of sub-authorities a SID can have
+const MAX_SUB_AUTHORITIES: usize = 15;
+
+/// A security identifier, such as `S-1-5-21-1004336348-1177238915-682003330-512`
+///
+/// # Example
+///
+/// ```
+/// use win_service_logger::Sid;
+///
+/// let sid: Sid = "S-1-5-18".parse().unwrap();
+/// assert_eq!(sid.as_bytes(), [1, 1, 0, 0, 0, 0, 0, 5, 18, 0, 0, 0]);
+/// assert_eq!(sid.to_string(), "S-1-5-18");
+///
+/// assert!("S-1-5-x".parse::<Sid>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sid {
+    /// The binary form of the SID, as used by the Windows API
+    bytes: Vec<u8>,
+}
+
+/// The reason a string or buffer isn't a valid [`Sid`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSid;
+
+impl fmt::Display for InvalidSid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid security identifier")
+    }
+}
+
+impl std::error::Error for InvalidSid {}
+
+impl Sid {
+    /// Creates a SID from its binary form
+    ///
+    /// # Errors
+    ///
+    /// Fails if `bytes` isn't a revision 1 SID with at most 15 sub-authorities
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidSid> {
+        match bytes {
+            [1, count, ..]
+                if usize::from(*count) <= MAX_SUB_AUTHORITIES
+                    && bytes.len() == 8 + 4 * usize::from(*count) =>
+            {
+                Ok(Self {
+                    bytes: bytes.to_vec(),
+                })
+            }
+            _ => Err(InvalidSid),
+        }
+    }
+
+    /// The binary form of the SID
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the SID of the user the current process runs as
+    ///
+    /// # Errors
+    ///
+    /// Returns the OS error if the process token can't be read
+    #[cfg(windows)]
+    pub fn current_user() -> std::io::Result<Self> {
+        win32::current_user()
+    }
+
+    fn authority(&self) -> u64 {
+        self.bytes[2..8]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | u64::from(b))
+    }
+
+    fn sub_authorities(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bytes[8..]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+    }
+}
+
+impl FromStr for Sid {
+    type Err = InvalidSid;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('-');
+        if !matches!(parts.next(), Some("S" | "s")) || parts.next() != Some("1") {
+            return Err(InvalidSid);
+        }
+
+        // The authority is 48 bits wide, and written in hex if it doesn't fit in 32 bits
+        let authority = parts.next().ok_or(InvalidSid)?;
+        let authority = match authority
+            .strip_prefix("0x")
+            .or(authority.strip_prefix("0X"))
+        {
+            Some(hex) => parse_number(hex, 16)?,
+            None => parse_number(authority, 10)?,
+        };
+        if authority >> 48 != 0 {
+            return Err(InvalidSid);
+        }
+
+        let mut bytes = vec![1, 0];
+        bytes.extend_from_slice(&authority.to_be_bytes()[2..]);
+        for sub_authority in parts {
+            let sub_authority =
+                u32::try_from(parse_number(sub_authority, 10)?).map_err(|_| InvalidSid)?;
+            bytes.extend_from_slice(&sub_authority.to_le_bytes());
+            bytes[1] += 1;
+            if usize::from(bytes[1]) > MAX_SUB_AUTHORITIES {
+                return Err(InvalidSid);
+            }
+        }
+        Ok(Self { bytes })
+    }
+}
+
+/// Parses a number made of nothing but digits, unlike `from_str_radix` which allows a sign
+fn parse_number(s: &str, radix: u32) -> Result<u64, InvalidSid> {
+    if s.is_empty() || !s.chars().all(|c| c.is_digit(radix)) {
+        return Err(InvalidSid);
+    }
+    u64::from_str_radix(s, radix).map_err(|_| InvalidSid)
+}
+
+impl fmt::Display for Sid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let authority = self.authority();
+        if authority >> 32 == 0 {
+            write!(f, "S-1-{}", authority)?;
+        } else {
+            write!(f, "S-1-0x{:012X}", authority)?;
+        }
+        for sub_authority in self.sub_authorities() {
+            write!(f, "-{}", sub_authority)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The SIDs of the live scopes on this thread, innermost last, with the ID of their scope
+    static SCOPED: RefCell<Vec<(u64, Sid)>> = const { RefCell::new(Vec::new()) };
+    static NEXT_SCOPE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Attaches a SID to every event logged on the current thread while the scope is alive
+///
+/// Scopes can be nested, the innermost one wins. Dropping a scope only removes its own SID,
+/// even if it is dropped before a scope entered after it. A [`USER_SID_KEY`] key-value on a
+/// record still takes precedence.
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{Logger, RecordingSink, UserSidScope};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Logger::new(sink.clone());
+/// let record = log::Record::builder()
+///     .args(format_args!("file deleted"))
+///     .level(log::Level::Info)
+///     .build();
+///
+/// {
+///     let _scope = UserSidScope::enter("S-1-5-21-1-2-3-1001".parse().unwrap());
+///     logger.log(&record);
+/// }
+/// logger.log(&record);
+///
+/// let outer = UserSidScope::enter("S-1-5-18".parse().unwrap());
+/// let inner = UserSidScope::enter("S-1-5-19".parse().unwrap());
+/// drop(outer);
+/// logger.log(&record);
+/// drop(inner);
+///
+/// let events = sink.events();
+/// assert_eq!(events[0].user_sid.as_ref().unwrap().to_string(), "S-1-5-21-1-2-3-1001");
+/// assert_eq!(events[1].user_sid, None);
+/// assert_eq!(events[2].user_sid.as_ref().unwrap().to_string(), "S-1-5-19");
+/// ```
+#[must_use = "the SID is only attached while the scope is alive"]
+#[derive(Debug)]
+pub struct UserSidScope {
+    id: u64,
+    // Scopes are tied to the thread-local stack they were pushed on
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl UserSidScope {
+    /// Attaches `sid` to events logged on this thread until the returned scope is dropped
+    pub fn enter(sid: Sid) -> Self {
+        let id = NEXT_SCOPE.with(|next| next.replace(next.get() + 1));
+        SCOPED.with(|scoped| scoped.borrow_mut().push((id, sid)));
+        Self {
+            id,
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for UserSidScope {
+    fn drop(&mut self) {
+        SCOPED.with(|scoped| {
+            let mut scoped = scoped.borrow_mut();
+            if let Some(index) = scoped.iter().rposition(|(id, _)| *id == self.id) {
+                scoped.remove(index);
+            }
+        });
+    }
+}
+
+/// Returns the SID for `record`, see the module documentation for the order
+pub(crate) fn user_sid(record: &Record, default: Option<&Sid>) -> Option<Sid> {
+    let from_record = record
+        .key_values()
+        .get(Key::from_str(USER_SID_KEY))
+        .and_then(|value| value.to_string().parse().ok());
+    from_record
+        .or_else(|| SCOPED.with(|scoped| Some(scoped.borrow().last()?.1.clone())))
+        .or_else(|| default.cloned())
+}
+
+#[cfg(windows)]
+mod win32 {
+    use std::io;
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+    use winapi::um::securitybaseapi::{GetLengthSid, GetTokenInformation};
+    use winapi::um::winnt::{TokenUser, TOKEN_QUERY, TOKEN_USER};
+
+    use super::Sid;
+
+    pub(super) fn current_user() -> io::Result<Sid> {
+        let mut token = std::ptr::null_mut();
+        // # Safety:
+        // 1. The pseudo handle from GetCurrentProcess doesn't need to be closed
+        // 2. WinAPI call
+        if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // TOKEN_USER is followed by the SID it points to, so ask for the size first
+        let mut size: DWORD = 0;
+        // # Safety:
+        // 1. A null buffer with a size of 0 only asks for the required size
+        // 2. WinAPI call
+        unsafe {
+            GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut size);
+        }
+        // u64s keep the buffer aligned for TOKEN_USER
+        let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+        // # Safety:
+        // 1. `buffer` has room for `size` bytes
+        // 2. WinAPI call
+        let ok = unsafe {
+            GetTokenInformation(
+                token,
+                TokenUser,
+                buffer.as_mut_ptr() as *mut _,
+                size,
+                &mut size,
+            )
+        };
+        let result = if ok == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            // # Safety:
+            // 1. GetTokenInformation succeeded, so `buffer` holds a TOKEN_USER whose SID is valid
+            // 2. The SID is `GetLengthSid` bytes long
+            unsafe {
+                let user = &*(buffer.as_ptr() as *const TOKEN_USER);
+                let sid = user.User.Sid;
+                let bytes =
+                    std::slice::from_raw_parts(sid as *const u8, GetLengthSid(sid) as usize);
+                Sid::from_bytes(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        };
+        // # Safety:
+        // WinAPI call
+        unsafe { CloseHandle(token) };
+        result
+    }
+}
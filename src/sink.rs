@@ -0,0 +1,539 @@
+//! Destinations for fully prepared events
+//!
+//! [`Logger`](crate::Logger) does all of the formatting and level mapping itself and then hands
+//! the finished [`Event`] to an [`EventSink`]. On Windows [`EventLogSink`] writes to the Event
+//! Viewer, while [`RecordingSink`] keeps events in memory so logging code can be tested anywhere.
+//! [`StderrSink`] is used in place of the Event Log on other platforms, and [`FileSink`] appends
+//! events to a text file, or [`RollingFileSink`] to a set of files of limited size.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::Level;
+
+use crate::Sid;
+
+/// The type of an event as shown in the "Level" column of Event Viewer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventType {
+    Error,
+    Warning,
+    Information,
+}
+
+impl EventType {
+    /// Returns the `EVENTLOG_*_TYPE` constant passed to `ReportEventW`
+    pub const fn raw(self) -> u16 {
+        // Values of EVENTLOG_ERROR_TYPE, EVENTLOG_WARNING_TYPE and EVENTLOG_INFORMATION_TYPE
+        // from winnt.h, spelled out so that this builds on every platform
+        match self {
+            EventType::Error => 0x0001,
+            EventType::Warning => 0x0002,
+            EventType::Information => 0x0004,
+        }
+    }
+}
+
+impl std::fmt::Display for EventType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EventType::Error => "Error",
+            EventType::Warning => "Warning",
+            EventType::Information => "Information",
+        })
+    }
+}
+
+impl From<Level> for EventType {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => EventType::Information,
+            Level::Debug => EventType::Information,
+            Level::Info => EventType::Information,
+            Level::Warn => EventType::Warning,
+            Level::Error => EventType::Error,
+        }
+    }
+}
+
+/// A single entry ready to be written to the event log
+///
+/// The fields map one to one onto the arguments of `ReportEventW`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub event_type: EventType,
+    pub category: u16,
+    pub event_id: u32,
+    /// Insertion strings, referenced as `%1`, `%2`, ... from a message table
+    pub strings: Vec<String>,
+    /// Binary data shown under "Details > Binary data" in Event Viewer
+    pub raw_data: Vec<u8>,
+    /// The user shown in Event Viewer
+    pub user_sid: Option<Sid>,
+}
+
+impl Event {
+    /// Creates an event with a single insertion string and no category, event ID, data or user
+    pub fn new(event_type: EventType, message: impl Into<String>) -> Self {
+        Self {
+            event_type,
+            category: 0,
+            event_id: 0,
+            strings: vec![message.into()],
+            raw_data: Vec::new(),
+            user_sid: None,
+        }
+    }
+}
+
+/// A destination for events produced by [`Logger`](crate::Logger)
+pub trait EventSink: Send + Sync {
+    /// Writes `event` to the underlying log
+    ///
+    /// # Errors
+    ///
+    /// Returns the OS error reported by the backend if the event could not be written
+    fn report(&self, event: &Event) -> io::Result<()>;
+
+    /// Writes out anything the sink buffers
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the backend if the buffered events could not be written
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Releases the resources held by the sink, such as the event source handle
+    ///
+    /// Events reported afterwards may fail. The default does nothing.
+    fn close(&self) {}
+}
+
+impl<S: EventSink + ?Sized> EventSink for Box<S> {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        (**self).report(event)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn close(&self) {
+        (**self).close()
+    }
+}
+
+impl<S: EventSink + ?Sized> EventSink for Arc<S> {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        (**self).report(event)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn close(&self) {
+        (**self).close()
+    }
+}
+
+/// An in-memory sink which records every event it receives
+///
+/// Clones share the same storage, so a clone can be given to a [`Logger`](crate::Logger) while
+/// the original is used to inspect what was written.
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{EventType, Logger, RecordingSink};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Logger::new(sink.clone());
+///
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("disk almost full"))
+///         .level(log::Level::Warn)
+///         .build(),
+/// );
+///
+/// let events = sink.events();
+/// assert_eq!(events.len(), 1);
+/// assert_eq!(events[0].event_type, EventType::Warning);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of every event recorded so far, oldest first
+    pub fn events(&self) -> Vec<Event> {
+        self.lock().clone()
+    }
+
+    /// Forgets all recorded events
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Event>> {
+        // A panic while holding the lock can't leave a `Vec::push` half done, so the data is
+        // still usable
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl EventSink for RecordingSink {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        self.lock().push(event.clone());
+        Ok(())
+    }
+}
+
+/// Prints events to stderr, one line per event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrSink;
+
+impl EventSink for StderrSink {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        write_line(&mut io::stderr().lock(), event)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Appends events to a file, one line per event, in the same layout as [`StderrSink`]
+#[derive(Debug)]
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if it doesn't exist
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be opened
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl EventSink for FileSink {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        // A panic while writing leaves at most a partial line behind
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        // Write the line in one go so concurrent writers to the file don't interleave
+        let mut line = Vec::new();
+        write_line(&mut line, event)?;
+        file.write_all(&line)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+/// Appends events to a file like [`FileSink`], starting a new file when it grows too large
+///
+/// When `service.log` would grow past the size limit it is renamed to `service.log.1`, the
+/// previous `service.log.1` to `service.log.2` and so on. Only `keep` old files are kept.
+///
+/// # Example
+///
+/// ```
+/// use win_service_logger::{Event, EventSink, EventType, RollingFileSink};
+///
+/// let dir = std::env::temp_dir().join(format!("rolling-doc-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let path = dir.join("service.log");
+///
+/// let sink = RollingFileSink::open(&path, 64, 1).unwrap();
+/// for i in 0..10 {
+///     let event = Event::new(EventType::Information, format!("request {} done", i));
+///     sink.report(&event).unwrap();
+/// }
+///
+/// assert!(std::fs::metadata(&path).unwrap().len() <= 64);
+/// assert!(dir.join("service.log.1").exists());
+/// assert!(!dir.join("service.log.2").exists());
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct RollingFileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    current: Mutex<(File, u64)>,
+}
+
+impl RollingFileSink {
+    /// Opens `path` for appending, starting a new file before it grows past `max_bytes`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be opened
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            current: Mutex::new((file, len)),
+        })
+    }
+
+    /// The name of the `n`th old file, `service.log.n`
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Moves the current file out of the way and opens a fresh one
+    fn rotate(&self) -> io::Result<File> {
+        if self.keep == 0 {
+            return File::create(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
+
+impl EventSink for RollingFileSink {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        let mut line = Vec::new();
+        write_line(&mut line, event)?;
+
+        // A panic while writing leaves at most a partial line behind
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let (file, len) = &mut *current;
+        if *len > 0 && *len + line.len() as u64 > self.max_bytes {
+            *file = self.rotate()?;
+            *len = 0;
+        }
+        file.write_all(&line)?;
+        *len += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        current.0.flush()
+    }
+}
+
+/// Returns whether the program runs in a console, as it does when it was started from a
+/// terminal rather than by the service control manager
+///
+/// On Windows this is true if a console is attached to the process, even with stderr redirected
+/// to a file or a pipe as IDEs do, and the process doesn't run in session 0. The service control
+/// manager starts every service in session 0, where no user logs on since Windows Vista, so a
+/// service which allocates a console for itself isn't mistaken for a console program. On other
+/// platforms this checks whether stderr is a terminal.
+pub fn has_console() -> bool {
+    #[cfg(windows)]
+    {
+        use winapi::um::processthreadsapi::{GetCurrentProcessId, ProcessIdToSessionId};
+
+        // # Safety:
+        // 1. WinAPI call without arguments, it returns null if there is no console
+        let console = !unsafe { winapi::um::wincon::GetConsoleWindow() }.is_null();
+        let mut session = 0;
+        // # Safety:
+        // 1. `session` is a valid place to write the session ID to
+        // 2. WinAPI call
+        let ok = unsafe { ProcessIdToSessionId(GetCurrentProcessId(), &mut session) };
+        // Without a session ID only the console is known, assume the program was started from it
+        console && (ok == 0 || session != 0)
+    }
+    #[cfg(not(windows))]
+    {
+        std::io::IsTerminal::is_terminal(&io::stderr())
+    }
+}
+
+fn write_line(out: &mut impl Write, event: &Event) -> io::Result<()> {
+    write!(out, "{}:", event.event_type)?;
+    for s in &event.strings {
+        write!(out, " {}", s)?;
+    }
+    writeln!(out)
+}
+
+#[cfg(windows)]
+pub use self::win32::EventLogSink;
+
+#[cfg(windows)]
+mod win32 {
+    use std::ffi::CString;
+    use std::io;
+
+    use winapi::um::winnt::HANDLE;
+
+    use super::{Event, EventSink};
+    use crate::{Backoff, EventSource, HandleManager};
+
+    /// Writes events to the Windows Event Log through `ReportEventW`
+    ///
+    /// Registration is retried as described in [`HandleManager`].
+    pub struct EventLogSink {
+        inner: HandleManager<Win32Source>,
+    }
+
+    struct Win32Source {
+        source_name: String,
+        machine: Option<String>,
+    }
+
+    struct Win32Handle(HANDLE);
+
+    // The handle returned by RegisterEventSourceA can be used from any thread, and the
+    // `HandleManager` only deregisters it once no `report` call is using it
+    unsafe impl Send for Win32Handle {}
+    unsafe impl Sync for Win32Handle {}
+
+    impl EventLogSink {
+        /// Creates a sink which reports events under the event source `source_name`
+        ///
+        /// The source is registered lazily when the first event is written
+        pub fn new(source_name: impl Into<String>) -> Self {
+            Self {
+                inner: HandleManager::new(Win32Source {
+                    source_name: source_name.into(),
+                    machine: None,
+                }),
+            }
+        }
+
+        /// Writes to the event log of the remote computer `machine` instead of the local one
+        ///
+        /// `machine` is a UNC name such as `\\server`
+        pub fn machine(mut self, machine: impl Into<String>) -> Self {
+            self.inner.source_mut().machine = Some(machine.into());
+            self
+        }
+
+        /// Sets how long to wait before registering again after `RegisterEventSourceA` failed
+        pub fn backoff(mut self, backoff: Backoff) -> Self {
+            self.inner = self.inner.backoff(backoff);
+            self
+        }
+    }
+
+    impl EventSink for EventLogSink {
+        fn report(&self, event: &Event) -> io::Result<()> {
+            self.inner.report(event)
+        }
+
+        /// Deregisters the event source, later events fail with [`io::ErrorKind::NotConnected`]
+        fn close(&self) {
+            self.inner.close();
+        }
+    }
+
+    impl EventSource for Win32Source {
+        type Handle = Win32Handle;
+
+        fn register(&self) -> io::Result<Win32Handle> {
+            let c_str = c_string(&self.source_name);
+            let machine = self.machine.as_deref().map(c_string);
+            let machine_ptr = machine.as_ref().map_or(std::ptr::null(), |m| m.as_ptr());
+            // # Safety:
+            // 1. `c_str` is a valid null terminated string
+            // 2. `machine_ptr` is null or a valid null terminated string
+            // 3. WinAPI call
+            let handle =
+                unsafe { winapi::um::winbase::RegisterEventSourceA(machine_ptr, c_str.as_ptr()) };
+            if handle.is_null() {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(Win32Handle(handle))
+            }
+        }
+
+        fn report(&self, handle: &Win32Handle, event: &Event) -> io::Result<()> {
+            let wide_strings: Vec<_> = event
+                .strings
+                .iter()
+                // Strings from a `Logger` have no NULs left, see `NulPolicy`, so this only
+                // cuts short events built by hand
+                .map(widestring::U16CString::from_str_truncate)
+                .collect();
+            let mut strings: Vec<_> = wide_strings.iter().map(|s| s.as_ptr()).collect();
+            let raw_data = if event.raw_data.is_empty() {
+                std::ptr::null_mut()
+            } else {
+                event.raw_data.as_ptr() as *mut _
+            };
+            let user_sid = event.user_sid.as_ref().map_or(std::ptr::null_mut(), |sid| {
+                sid.as_bytes().as_ptr() as *mut _
+            });
+
+            // # Safety:
+            // 1. Every pointer in `strings` points to a null terminated utf-16 string in
+            //    `wide_strings`, which outlives the call
+            // 2. The length of `strings` is passed as the string count
+            // 3. `raw_data` is either null or points to `raw_data.len()` readable bytes
+            // 4. `user_sid` is either null or points to a valid binary SID, see `Sid::from_bytes`
+            // 5. The `HandleManager` keeps `handle` registered until the call returns
+            // 6. WinAPI call
+            let ok = unsafe {
+                winapi::um::winbase::ReportEventW(
+                    handle.0,
+                    event.event_type.raw(),
+                    event.category,
+                    event.event_id,
+                    user_sid,
+                    strings.len() as u16,
+                    event.raw_data.len() as u32,
+                    strings.as_mut_ptr(),
+                    raw_data,
+                )
+            };
+            if ok == 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn deregister(&self, handle: Win32Handle) {
+            // # Safety:
+            // 1. The `HandleManager` only deregisters a handle once, when no call is using it
+            // 2. WinAPI call
+            let _ = unsafe { winapi::um::winbase::DeregisterEventSource(handle.0) };
+        }
+    }
+
+    /// Converts `s` to a C string, leaving out any NULs
+    fn c_string(s: &str) -> CString {
+        CString::new(s.replace('\0', "")).unwrap_or_default()
+    }
+}
@@ -0,0 +1,268 @@
+//! Keeping events within the size limits of the event log
+//!
+//! `ReportEventW` fails for insertion strings longer than [`MAX_STRING_LEN`] characters and for
+//! events larger than about 61 KB in total. The [`Oversize`] policy set with
+//! [`Builder::oversize`](crate::Builder::oversize) decides what happens to events which are too
+//! large: their strings are either cut short, or spread over several events.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use log::Record;
+
+use crate::Event;
+
+/// The longest insertion string the event log accepts, in UTF-16 code units
+pub const MAX_STRING_LEN: usize = 31_839;
+
+/// The largest event the event log accepts, in bytes of UTF-16 strings plus raw data
+pub const MAX_EVENT_SIZE: usize = 61_440;
+
+/// Code units kept free for the part or truncation marker
+const MARKER_RESERVE: usize = 40;
+
+/// Appended to strings that were cut short
+const TRUNCATED: &str = " [truncated]";
+
+/// What to do with events that are too large for the event log
+///
+/// # Example
+///
+/// ```
+/// use log::Log;
+/// use win_service_logger::{format, Builder, Field, Oversize, RecordingSink, MAX_STRING_LEN};
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Json)
+///     .sink(sink.clone())
+///     .build();
+/// let dump = "x".repeat(70_000);
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("{}", dump))
+///         .level(log::Level::Error)
+///         .build(),
+/// );
+///
+/// // The message is cut short, not the JSON object
+/// let json = &sink.events()[0].strings[0];
+/// assert!(json.len() <= MAX_STRING_LEN);
+/// assert!(json.ends_with(r#"xxx [truncated]","fields":{}}"#));
+///
+/// let sink = RecordingSink::new();
+/// let logger = Builder::new()
+///     .formatter(format::Message)
+///     .fields([Field::Level, Field::Key("dump".into())])
+///     .oversize(Oversize::Split)
+///     .sink(sink.clone())
+///     .build();
+/// let kvs = [("dump", dump.as_str())];
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("core dumped"))
+///         .level(log::Level::Error)
+///         .key_values(&kvs)
+///         .build(),
+/// );
+///
+/// // Without a message field the parts carry no marker
+/// let events = sink.events();
+/// assert_eq!(events.len(), 3);
+/// assert!(events.iter().all(|e| e.strings[0] == "ERROR"));
+/// let joined: String = events.iter().map(|e| e.strings[1].as_str()).collect();
+/// assert_eq!(joined, dump);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Oversize {
+    /// Cut the longest strings short and mark them with `[truncated]`
+    ///
+    /// With a [structured](crate::Formatter::is_structured) formatter such as
+    /// [`Json`](crate::format::Json) the message of the record is cut short before it is
+    /// formatted, so the output stays valid. Only if that isn't enough, because the other parts of
+    /// the record are too large as well, is the formatted string cut like any other.
+    #[default]
+    Truncate,
+    /// Write the event as several events, each prefixed with `[part 2/3 id=...]`
+    ///
+    /// Strings that don't fit are spread over the parts, shorter strings are repeated in every
+    /// part. The marker goes in front of the [message](crate::Field::Message), and all parts of
+    /// an event share the same correlation ID. Without a message field there is no marker. Raw
+    /// data is only attached to the first part.
+    ///
+    /// Each part holds a piece of the original string, so the output of a structured formatter
+    /// such as [`Json`](crate::format::Json) can only be parsed once the pieces, without their
+    /// markers, are joined again.
+    Split,
+}
+
+/// Returns `event` as one or more events which fit in the event log
+///
+/// `marker` is the index of the string the split marker is put in front of, if there is one
+pub(crate) fn fit(mut event: Event, policy: Oversize, marker: Option<usize>) -> Vec<Event> {
+    if excess(&event) == 0 {
+        return vec![event];
+    }
+
+    // Raw data may take at most half of the event, the rest is left for the strings
+    event.raw_data.truncate(MAX_EVENT_SIZE / 2);
+    let budget = ((MAX_EVENT_SIZE - event.raw_data.len()) / 2)
+        .saturating_sub(event.strings.len() * (1 + MARKER_RESERVE));
+    let lens: Vec<usize> = event.strings.iter().map(|s| utf16_len(s)).collect();
+    let cap = cap(&lens, budget).clamp(1, MAX_STRING_LEN - MARKER_RESERVE);
+
+    match policy {
+        Oversize::Truncate => {
+            for string in &mut event.strings {
+                if utf16_len(string) > cap {
+                    let end = split_point(string, cap);
+                    string.truncate(end);
+                    string.push_str(TRUNCATED);
+                }
+            }
+            vec![event]
+        }
+        Oversize::Split => split(event, cap, marker),
+    }
+}
+
+/// Formats `record` again with a shorter message if `event` is too large for the event log
+///
+/// `strings` builds the insertion strings of a record. `event` is left as it is if even an empty
+/// message doesn't make it fit.
+pub(crate) fn truncate_message(
+    record: &Record,
+    event: &mut Event,
+    strings: impl Fn(&Record) -> Vec<String>,
+) {
+    let excess = excess(event);
+    if excess == 0 {
+        return;
+    }
+    let message = record.args().to_string();
+    // Formatting only adds to the message, so each code unit cut saves at least one
+    let keep = match utf16_len(&message).checked_sub(excess + TRUNCATED.len()) {
+        Some(keep) if keep > 0 => keep,
+        _ => return,
+    };
+    let message = &message[..split_point(&message, keep)];
+    let shorter = strings(
+        &record
+            .to_builder()
+            .args(format_args!("{}{}", message, TRUNCATED))
+            .build(),
+    );
+    let before = std::mem::replace(&mut event.strings, shorter);
+    if self::excess(event) > 0 {
+        event.strings = before;
+    }
+}
+
+/// How far `event` is over the limits of the event log, in UTF-16 code units
+fn excess(event: &Event) -> usize {
+    let longest = event.strings.iter().map(|s| utf16_len(s)).max();
+    let over_string = longest.unwrap_or(0).saturating_sub(MAX_STRING_LEN);
+    let over_event = size(event).saturating_sub(MAX_EVENT_SIZE).div_ceil(2);
+    over_string.max(over_event)
+}
+
+fn split(event: Event, cap: usize, marker: Option<usize>) -> Vec<Event> {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let chunks: Vec<Vec<&str>> = event
+        .strings
+        .iter()
+        .map(|string| {
+            if utf16_len(string) <= cap {
+                return vec![string.as_str()];
+            }
+            let mut chunks = Vec::new();
+            let mut rest = string.as_str();
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(split_point(rest, cap));
+                chunks.push(chunk);
+                rest = tail;
+            }
+            chunks
+        })
+        .collect();
+    let parts = chunks.iter().map(Vec::len).max().unwrap_or(1);
+
+    (0..parts)
+        .map(|part| {
+            let strings = chunks
+                .iter()
+                .enumerate()
+                .map(|(i, chunks)| {
+                    // Repeat strings that fit, leave the ones that ran out empty
+                    let chunk = match chunks.len() {
+                        1 => chunks[0],
+                        _ => chunks.get(part).copied().unwrap_or_default(),
+                    };
+                    if Some(i) == marker {
+                        format!(
+                            "[part {}/{} id={:08x}] {}",
+                            part + 1,
+                            parts,
+                            std::process::id() ^ id.rotate_left(16),
+                            chunk
+                        )
+                    } else {
+                        chunk.to_owned()
+                    }
+                })
+                .collect();
+            Event {
+                event_type: event.event_type,
+                category: event.category,
+                event_id: event.event_id,
+                strings,
+                raw_data: if part == 0 {
+                    event.raw_data.clone()
+                } else {
+                    Vec::new()
+                },
+                user_sid: event.user_sid.clone(),
+            }
+        })
+        .collect()
+}
+
+/// The size of `event` as `ReportEventW` sees it, with NUL terminated UTF-16 strings
+fn size(event: &Event) -> usize {
+    let strings: usize = event.strings.iter().map(|s| 2 * (utf16_len(s) + 1)).sum();
+    strings + event.raw_data.len()
+}
+
+/// The largest length each string can keep so that all of them fit in `budget` code units
+fn cap(lens: &[usize], budget: usize) -> usize {
+    let mut sorted = lens.to_vec();
+    sorted.sort_unstable();
+    let mut remaining = budget;
+    for (i, &len) in sorted.iter().enumerate() {
+        let share = remaining / (sorted.len() - i);
+        if len > share {
+            return share;
+        }
+        remaining -= len;
+    }
+    usize::MAX
+}
+
+fn utf16_len(s: &str) -> usize {
+    s.chars().map(char::len_utf16).sum()
+}
+
+/// The byte index of the longest prefix of `s` that is at most `max` UTF-16 code units long
+///
+/// At least one character is kept so splitting always makes progress.
+fn split_point(s: &str, max: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in s.char_indices() {
+        units += c.len_utf16();
+        if units > max {
+            return if index == 0 { c.len_utf8() } else { index };
+        }
+    }
+    s.len()
+}
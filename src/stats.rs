@@ -0,0 +1,106 @@
+//! Counting events that couldn't be written
+//!
+//! The logger can't report its own failures through the event log, so it counts them instead.
+//! [`Logger::stats`](crate::Logger::stats) returns the counts, an error callback set with
+//! [`Builder::on_error`](crate::Builder::on_error) is called for every failure, and a fallback
+//! sink set with [`Builder::fallback`](crate::Builder::fallback) gets the events that were lost.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Counts of the events a [`Logger`](crate::Logger) has written
+///
+/// # Example
+///
+/// ```
+/// use std::io;
+///
+/// use log::Log;
+/// use win_service_logger::{Builder, Event, EventSink, RecordingSink};
+///
+/// struct Broken;
+///
+/// impl EventSink for Broken {
+///     fn report(&self, _: &Event) -> io::Result<()> {
+///         Err(io::Error::from_raw_os_error(1502))
+///     }
+/// }
+///
+/// let fallback = RecordingSink::new();
+/// let logger = Builder::new()
+///     .sink(Broken)
+///     .fallback(fallback.clone())
+///     .on_error(|err, event| eprintln!("lost {:?}: {}", event.strings, err))
+///     .build();
+/// logger.log(
+///     &log::Record::builder()
+///         .args(format_args!("disk full"))
+///         .level(log::Level::Error)
+///         .build(),
+/// );
+///
+/// let stats = logger.stats();
+/// assert_eq!(stats.written, 0);
+/// assert_eq!(stats.failed, 1);
+/// assert_eq!(stats.dropped, 0);
+/// assert_eq!(stats.suppressed, 0);
+/// assert_eq!(stats.last_error, Some(1502));
+/// assert_eq!(fallback.events().len(), 1);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// Events written to the sink
+    pub written: u64,
+    /// Events the sink failed to write
+    pub failed: u64,
+    /// Events dropped because the [async queue](crate::Builder::async_queue) was full, or the
+    /// logger was [shut down](crate::Logger::shutdown)
+    pub dropped: u64,
+    /// Events not written because of the [rate limit](crate::Builder::rate_limit)
+    pub suppressed: u64,
+    /// The OS error code of the most recent failure, if it had one
+    pub last_error: Option<i32>,
+}
+
+/// The live counters behind [`Stats`]
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    written: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+    suppressed: AtomicU64,
+    last_error: Mutex<Option<i32>>,
+}
+
+impl Counters {
+    pub(crate) fn success(&self) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failure(&self, err: &io::Error) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = err.raw_os_error();
+    }
+
+    pub(crate) fn drop_event(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn suppress(&self) {
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        Stats {
+            written: self.written.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            suppressed: self.suppressed.load(Ordering::Relaxed),
+            last_error: *self.last_error.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+}
+
+/// Called with every error a sink returns, and the event that was lost
+pub(crate) type ErrorCallback = Box<dyn Fn(&io::Error, &crate::Event) + Send + Sync>;
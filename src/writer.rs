@@ -0,0 +1,145 @@
+//! Handing finished events to the sinks
+//!
+//! [`Writer`] holds everything that happens after an event is built: the main sink, the tees,
+//! the failure counters, the error callback and the fallback. It is shared between the
+//! [`Logger`](crate::Logger) and the background thread of an async queue.
+
+use std::cell::RefCell;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::stats::{Counters, ErrorCallback};
+use crate::{Event, EventSink};
+
+pub(crate) struct Writer {
+    pub(crate) sink: Box<dyn EventSink>,
+    pub(crate) tees: Vec<Box<dyn EventSink>>,
+    pub(crate) fallback: Option<Box<dyn EventSink>>,
+    pub(crate) on_error: Option<ErrorCallback>,
+    pub(crate) stats: Counters,
+    gate: Mutex<Gate>,
+    /// Signalled when a write finishes
+    idle: Condvar,
+}
+
+/// Lets [`Writer::close`] wait for the writes in progress without holding a lock while the sinks
+/// and the error callback run, as either may log or shut down the logger
+#[derive(Default)]
+struct Gate {
+    closed: bool,
+    /// Writes in progress
+    writing: usize,
+}
+
+thread_local! {
+    /// The writers this thread is writing to, more than one if a sink or callback logs
+    static WRITING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Counts a write as in progress until it is dropped
+struct Writing<'a>(&'a Writer);
+
+impl<'a> Writing<'a> {
+    /// Returns `None` if the writer is closed
+    fn enter(writer: &'a Writer) -> Option<Self> {
+        let mut gate = writer.gate();
+        if gate.closed {
+            return None;
+        }
+        gate.writing += 1;
+        WRITING.with(|writing| writing.borrow_mut().push(writer.id()));
+        Some(Self(writer))
+    }
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        WRITING.with(|writing| writing.borrow_mut().pop());
+        self.0.gate().writing -= 1;
+        self.0.idle.notify_all();
+    }
+}
+
+impl Writer {
+    pub(crate) fn new(sink: Box<dyn EventSink>) -> Self {
+        Self {
+            sink,
+            tees: Vec::new(),
+            fallback: None,
+            on_error: None,
+            stats: Counters::default(),
+            gate: Mutex::default(),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Writes `event` to the sink and the tees, counting and forwarding failures of the sink
+    pub(crate) fn write(&self, event: &Event) {
+        // Closing waits for the writes in progress, later ones are dropped
+        let Some(_writing) = Writing::enter(self) else {
+            self.stats.drop_event();
+            return;
+        };
+        for tee in &self.tees {
+            let _ = tee.report(event);
+        }
+        match self.sink.report(event) {
+            Ok(()) => self.stats.success(),
+            Err(err) => {
+                self.stats.failure(&err);
+                if let Some(on_error) = &self.on_error {
+                    on_error(&err, event);
+                }
+                // The callback may have shut the logger down
+                if let Some(fallback) = self.fallback.as_ref().filter(|_| !self.gate().closed) {
+                    let _ = fallback.report(event);
+                }
+            }
+        }
+    }
+
+    /// Flushes the sink, the tees and the fallback
+    pub(crate) fn flush(&self) {
+        for sink in self.sinks() {
+            let _ = sink.flush();
+        }
+    }
+
+    /// Flushes and closes the sink, the tees and the fallback, later events are dropped
+    pub(crate) fn close(&self) {
+        let mut gate = self.gate();
+        if gate.closed {
+            return;
+        }
+        gate.closed = true;
+        // Writes further up this thread's stack, which a sink or callback closed the writer
+        // from, can't finish before this returns
+        let own = WRITING.with(|writing| {
+            let writing = writing.borrow();
+            writing.iter().filter(|&&id| id == self.id()).count()
+        });
+        while gate.writing > own {
+            gate = self.idle.wait(gate).unwrap_or_else(|e| e.into_inner());
+        }
+        drop(gate);
+
+        for sink in self.sinks() {
+            let _ = sink.flush();
+            sink.close();
+        }
+    }
+
+    fn gate(&self) -> MutexGuard<'_, Gate> {
+        self.gate.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Tells writers apart in [`WRITING`], they are shared behind an `Arc` and don't move
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn sinks(&self) -> impl Iterator<Item = &dyn EventSink> {
+        std::iter::once(&*self.sink)
+            .chain(self.tees.iter().map(|tee| &**tee))
+            .chain(self.fallback.as_deref())
+    }
+}
@@ -0,0 +1,228 @@
+//! Overflow policies of the async queue, checked with a sink that blocks until it is released
+
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+mod common;
+
+use log::{Level, Log};
+use win_service_logger::{Builder, Event, EventSink, Field, Logger, Overflow, RecordingSink};
+
+use common::{builder, log, messages};
+
+/// Signals on `entered` when it starts writing an event, then waits for a message on `gate`
+/// before writing the event to `inner`
+struct GatedSink {
+    entered: Mutex<Sender<()>>,
+    gate: Mutex<Receiver<()>>,
+    inner: RecordingSink,
+}
+
+impl EventSink for GatedSink {
+    fn report(&self, event: &Event) -> io::Result<()> {
+        let _ = self.entered.lock().unwrap().send(());
+        let _ = self.gate.lock().unwrap().recv();
+        self.inner.report(event)
+    }
+}
+
+/// The test's side of a [`GatedSink`]
+struct Gate {
+    /// Lets one event through
+    open: Sender<()>,
+    /// Receives a message whenever the sink starts writing an event
+    entered: Receiver<()>,
+}
+
+fn logger(overflow: Overflow) -> (Logger, Gate, RecordingSink) {
+    gated(builder(), overflow)
+}
+
+fn gated(builder: Builder, overflow: Overflow) -> (Logger, Gate, RecordingSink) {
+    let (open, gate) = channel();
+    let (entered, entered_rx) = channel();
+    let sink = RecordingSink::new();
+    let logger = builder
+        .async_queue(2, overflow)
+        .sink(GatedSink {
+            entered: Mutex::new(entered),
+            gate: Mutex::new(gate),
+            inner: sink.clone(),
+        })
+        .build();
+    let gate = Gate {
+        open,
+        entered: entered_rx,
+    };
+    (logger, gate, sink)
+}
+
+/// Gets the background thread stuck writing event 1, then fills the queue with events 2 and 3
+/// and logs events 4 and 5 into the full queue
+fn overflow(logger: &Logger, gate: &Gate) {
+    log(logger, Level::Info, 0, 0);
+    // Let event 0 through, then wait for the thread to get stuck on the gate with event 1
+    gate.open.send(()).unwrap();
+    log(logger, Level::Info, 0, 1);
+    for _ in 0..2 {
+        gate.entered.recv().unwrap();
+    }
+    for i in 2..6 {
+        log(logger, Level::Info, 0, i);
+    }
+}
+
+fn release(logger: Logger, gate: Gate) -> win_service_logger::Stats {
+    for _ in 0..10 {
+        let _ = gate.open.send(());
+    }
+    logger.flush();
+    let stats = logger.stats();
+    drop(logger);
+    stats
+}
+
+#[test]
+fn drop_newest() {
+    let (logger, gate, sink) = logger(Overflow::DropNewest);
+    overflow(&logger, &gate);
+    let stats = release(logger, gate);
+    assert_eq!(messages(&sink), ["0", "1", "2", "3"]);
+    assert_eq!(stats.dropped, 2);
+}
+
+#[test]
+fn drop_oldest() {
+    let (logger, gate, sink) = logger(Overflow::DropOldest);
+    overflow(&logger, &gate);
+    let stats = release(logger, gate);
+    assert_eq!(messages(&sink), ["0", "1", "4", "5"]);
+    assert_eq!(stats.dropped, 2);
+}
+
+#[test]
+fn summarize() {
+    let (logger, gate, sink) = logger(Overflow::Summarize);
+    overflow(&logger, &gate);
+    let stats = release(logger, gate);
+    assert_eq!(
+        messages(&sink),
+        [
+            "0",
+            "1",
+            "2",
+            "3",
+            "2 events were dropped because the log queue was full"
+        ]
+    );
+    assert_eq!(stats.dropped, 2);
+}
+
+#[test]
+fn summary_uses_the_fields_and_event_ids() {
+    let builder = builder()
+        .fields([Field::Level, Field::Message])
+        .event_id(Level::Info, 42)
+        .event_id(Level::Warn, 43);
+    let (logger, gate, sink) = gated(builder, Overflow::Summarize);
+    overflow(&logger, &gate);
+    release(logger, gate);
+
+    let events: Vec<_> = sink
+        .events()
+        .into_iter()
+        .map(|event| (event.event_id, event.strings))
+        .collect();
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[0], (42, vec!["INFO".to_owned(), "0".to_owned()]));
+    assert_eq!(
+        events[4],
+        (
+            43,
+            vec![
+                "WARN".to_owned(),
+                "2 events were dropped because the log queue was full".to_owned()
+            ]
+        )
+    );
+}
+
+#[test]
+fn block() {
+    let (logger, gate, sink) = logger(Overflow::Block);
+    let logger = Arc::new(logger);
+    let writer = {
+        let logger = Arc::clone(&logger);
+        std::thread::spawn(move || {
+            for i in 0..6 {
+                log(&logger, Level::Info, 0, i);
+            }
+        })
+    };
+    for _ in 0..6 {
+        gate.open.send(()).unwrap();
+    }
+    writer.join().unwrap();
+    logger.flush();
+    assert_eq!(messages(&sink), ["0", "1", "2", "3", "4", "5"]);
+    assert_eq!(logger.stats().dropped, 0);
+}
+
+#[test]
+fn shutdown_writes_queued_events() {
+    let (logger, gate, sink) = logger(Overflow::Block);
+    log(&logger, Level::Info, 0, 0);
+    log(&logger, Level::Info, 0, 1);
+    for _ in 0..2 {
+        gate.open.send(()).unwrap();
+    }
+    logger.shutdown();
+    log(&logger, Level::Info, 0, 2);
+    assert_eq!(messages(&sink), ["0", "1"]);
+    assert_eq!(logger.stats().dropped, 1);
+}
+
+#[test]
+fn blocked_events_are_dropped_on_shutdown() {
+    let (logger, gate, sink) = logger(Overflow::Block);
+    let logger = Arc::new(logger);
+    // Event 0 gets stuck in the sink, 1 and 2 fill the queue and 3 waits for room
+    let producer = {
+        let logger = Arc::clone(&logger);
+        std::thread::spawn(move || {
+            for i in 0..5 {
+                log(&logger, Level::Info, 0, i);
+            }
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let shutdown = {
+        let logger = Arc::clone(&logger);
+        std::thread::spawn(move || logger.shutdown())
+    };
+    // Shutting down wakes the producer without making room
+    producer.join().unwrap();
+    for _ in 0..3 {
+        gate.open.send(()).unwrap();
+    }
+    shutdown.join().unwrap();
+    assert_eq!(messages(&sink), ["0", "1", "2"]);
+    assert_eq!(logger.stats().dropped, 2);
+}
+
+#[test]
+fn flush_waits_on_a_thread_with_the_writer_name() {
+    let (logger, gate, sink) = logger(Overflow::Block);
+    std::thread::Builder::new()
+        .name("event-log-writer".into())
+        .spawn(move || {
+            log(&logger, Level::Info, 0, 0);
+            gate.open.send(()).unwrap();
+            logger.flush();
+            assert_eq!(messages(&sink), ["0"]);
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
@@ -0,0 +1,31 @@
+//! Helpers shared by the integration tests
+
+use std::fmt::Display;
+
+use log::{Level, Log, Record};
+use win_service_logger::{format, Builder, Logger, RecordingSink};
+
+/// A builder whose events only hold the message, so tests can compare them as plain strings
+pub fn builder() -> Builder {
+    Builder::new().formatter(format::Message)
+}
+
+/// Logs `message` at `level` from line `line` of `src/main.rs`
+pub fn log(logger: &Logger, level: Level, line: u32, message: impl Display) {
+    logger.log(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(level)
+            .file(Some("src/main.rs"))
+            .line(Some(line))
+            .build(),
+    );
+}
+
+/// The first insertion string of every event written to `sink`
+pub fn messages(sink: &RecordingSink) -> Vec<String> {
+    sink.events()
+        .into_iter()
+        .map(|event| event.strings[0].clone())
+        .collect()
+}
@@ -0,0 +1,188 @@
+//! The retry state machine of `HandleManager`, driven by a fake event source
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use win_service_logger::{Backoff, Event, EventSink, EventSource, EventType, HandleManager};
+
+/// RPC_S_SERVER_UNAVAILABLE, returned while the EventLog service is down
+const UNAVAILABLE: i32 = 1722;
+
+#[derive(Default)]
+struct Calls {
+    /// Results of the next `register` calls, success once empty
+    register: VecDeque<Result<(), i32>>,
+    /// Handles whose writes fail
+    broken: Vec<u32>,
+    registered: u32,
+    deregistered: Vec<u32>,
+    written: Vec<u32>,
+}
+
+#[derive(Clone, Default)]
+struct FakeSource(Arc<Mutex<Calls>>);
+
+impl FakeSource {
+    fn calls(&self) -> std::sync::MutexGuard<'_, Calls> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl EventSource for FakeSource {
+    type Handle = u32;
+
+    fn register(&self) -> io::Result<u32> {
+        let mut calls = self.calls();
+        match calls.register.pop_front() {
+            Some(Err(code)) => Err(io::Error::from_raw_os_error(code)),
+            _ => {
+                calls.registered += 1;
+                Ok(calls.registered)
+            }
+        }
+    }
+
+    fn report(&self, handle: &u32, _: &Event) -> io::Result<()> {
+        let mut calls = self.calls();
+        if calls.broken.contains(handle) {
+            return Err(io::Error::from_raw_os_error(UNAVAILABLE));
+        }
+        calls.written.push(*handle);
+        Ok(())
+    }
+
+    fn deregister(&self, handle: u32) {
+        self.calls().deregistered.push(handle);
+    }
+}
+
+fn event() -> Event {
+    Event::new(EventType::Information, "hello")
+}
+
+/// A clock which only moves when it is advanced
+#[derive(Clone)]
+struct FakeClock(Arc<Mutex<Instant>>);
+
+impl FakeClock {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[test]
+fn registers_lazily_once() {
+    let source = FakeSource::default();
+    let sink = HandleManager::new(source.clone());
+    assert_eq!(source.calls().registered, 0);
+
+    sink.report(&event()).unwrap();
+    sink.report(&event()).unwrap();
+    assert_eq!(source.calls().registered, 1);
+    assert_eq!(source.calls().written, [1, 1]);
+}
+
+#[test]
+fn waits_for_the_backoff_after_a_failed_registration() {
+    let source = FakeSource::default();
+    source
+        .calls()
+        .register
+        .extend([Err(UNAVAILABLE), Err(UNAVAILABLE)]);
+    let clock = FakeClock::new();
+    let sink = HandleManager::new(source.clone())
+        .backoff(Backoff::new(
+            Duration::from_millis(200),
+            Duration::from_secs(60),
+        ))
+        .clock({
+            let clock = clock.clone();
+            move || clock.now()
+        });
+
+    let err = sink.report(&event()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(UNAVAILABLE));
+    // Still within the backoff, so the failure is returned without calling register again
+    let err = sink.report(&event()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(UNAVAILABLE));
+    assert_eq!(source.calls().register.len(), 1);
+
+    clock.advance(Duration::from_millis(199));
+    assert!(sink.report(&event()).is_err());
+    assert_eq!(source.calls().register.len(), 1);
+    clock.advance(Duration::from_millis(1));
+    assert!(sink.report(&event()).is_err());
+    assert!(source.calls().register.is_empty());
+
+    // The second failure doubled the delay to 400ms
+    clock.advance(Duration::from_millis(399));
+    assert!(sink.report(&event()).is_err());
+    assert!(source.calls().written.is_empty());
+    clock.advance(Duration::from_millis(1));
+    sink.report(&event()).unwrap();
+    assert_eq!(source.calls().written, [1]);
+}
+
+#[test]
+fn registers_again_when_a_write_fails() {
+    let source = FakeSource::default();
+    let sink = HandleManager::new(source.clone());
+    sink.report(&event()).unwrap();
+
+    // The EventLog service restarted, handle 1 no longer works
+    source.calls().broken.push(1);
+    sink.report(&event()).unwrap();
+
+    let calls = source.calls();
+    assert_eq!(calls.written, [1, 2]);
+    assert_eq!(calls.deregistered, [1]);
+}
+
+#[test]
+fn stale_handle_is_replaced_once() {
+    let source = FakeSource::default();
+    let sink = Arc::new(HandleManager::new(source.clone()));
+    sink.report(&event()).unwrap();
+    source.calls().broken.push(1);
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let sink = Arc::clone(&sink);
+            std::thread::spawn(move || sink.report(&event()).unwrap())
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let calls = source.calls();
+    assert_eq!(calls.registered, 2);
+    assert_eq!(calls.deregistered, [1]);
+    assert_eq!(calls.written.iter().filter(|&&h| h == 2).count(), 8);
+}
+
+#[test]
+fn close_deregisters_once() {
+    let source = FakeSource::default();
+    let sink = HandleManager::new(source.clone());
+    sink.report(&event()).unwrap();
+
+    sink.close();
+    let err = sink.report(&event()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    drop(sink);
+
+    let calls = source.calls();
+    assert_eq!(calls.registered, 1);
+    assert_eq!(calls.deregistered, [1]);
+}
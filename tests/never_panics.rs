@@ -0,0 +1,142 @@
+//! Property tests checking that logging never panics and only produces events the event log
+//! accepts, whatever the contents of the record
+
+use log::{Level, Log, Record};
+use proptest::prelude::*;
+use win_service_logger::{
+    Builder, Event, Field, NulPolicy, Oversize, RecordingSink, Sid, MAX_EVENT_SIZE, MAX_STRING_LEN,
+};
+
+fn level() -> impl Strategy<Value = Level> {
+    prop_oneof![
+        Just(Level::Error),
+        Just(Level::Warn),
+        Just(Level::Info),
+        Just(Level::Debug),
+        Just(Level::Trace),
+    ]
+}
+
+fn nul_policy() -> impl Strategy<Value = NulPolicy> {
+    prop_oneof![
+        Just(NulPolicy::Escape),
+        Just(NulPolicy::Strip),
+        any::<char>().prop_map(NulPolicy::Replace),
+    ]
+}
+
+fn oversize() -> impl Strategy<Value = Oversize> {
+    prop_oneof![Just(Oversize::Truncate), Just(Oversize::Split)]
+}
+
+fn field() -> impl Strategy<Value = Field> {
+    prop_oneof![
+        Just(Field::Message),
+        Just(Field::Level),
+        Just(Field::Target),
+        Just(Field::ModulePath),
+        Just(Field::File),
+        Just(Field::Line),
+        Just(Field::KeyValues),
+        "[a-z_]{1,10}".prop_map(Field::Key),
+    ]
+}
+
+/// Key-values, with the keys the logger reads itself showing up often
+fn key_values() -> impl Strategy<Value = Vec<(String, String)>> {
+    let key = prop_oneof![
+        Just("event_id".to_owned()),
+        Just("category".to_owned()),
+        Just("raw_data".to_owned()),
+        Just("user_sid".to_owned()),
+        ".{0,10}",
+    ];
+    let value = prop_oneof![".{0,40}", "[0-9a-f]{0,64}", "S-1-[0-9-]{0,40}"];
+    prop::collection::vec((key, value), 0..6)
+}
+
+/// Messages of any characters, sometimes repeated past the size limits of the event log
+fn message() -> impl Strategy<Value = String> {
+    prop_oneof![
+        3 => ".{0,100}",
+        1 => (".{1,20}", 1_000..10_000usize).prop_map(|(s, n)| s.repeat(n)),
+    ]
+}
+
+fn check(event: &Event) {
+    let mut size = event.raw_data.len();
+    for string in &event.strings {
+        assert!(!string.contains('\0'), "NUL in {:?}", string);
+        let len = string.encode_utf16().count();
+        assert!(len <= MAX_STRING_LEN, "string of {} code units", len);
+        size += 2 * (len + 1);
+    }
+    assert!(size <= MAX_EVENT_SIZE, "event of {} bytes", size);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn log_never_panics(
+        msg in message(),
+        level in level(),
+        target in ".{0,20}",
+        file in proptest::option::of(".{0,20}"),
+        line in proptest::option::of(any::<u32>()),
+        kvs in key_values(),
+        fields in prop::collection::vec(field(), 0..5),
+        nul_policy in nul_policy(),
+        oversize in oversize(),
+        raw_data_limit in 0..100_000usize,
+    ) {
+        let sink = RecordingSink::new();
+        let mut builder = Builder::new()
+            .nul_policy(nul_policy)
+            .oversize(oversize)
+            .raw_data_limit(raw_data_limit)
+            .sink(sink.clone());
+        if !fields.is_empty() {
+            builder = builder.fields(fields);
+        }
+        let logger = builder.build();
+        let kvs: Vec<(&str, &str)> = kvs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", msg))
+                .level(level)
+                .target(&target)
+                .file(file.as_deref())
+                .line(line)
+                .key_values(&kvs.as_slice())
+                .build(),
+        );
+
+        let events = sink.events();
+        prop_assert!(!events.is_empty());
+        for event in &events {
+            check(event);
+        }
+    }
+
+    #[test]
+    fn sid_parsing_never_panics(s in ".{0,80}", bytes in prop::collection::vec(any::<u8>(), 0..80)) {
+        if let Ok(sid) = s.parse::<Sid>() {
+            prop_assert_eq!(Sid::from_bytes(sid.as_bytes()), Ok(sid.clone()));
+        }
+        if let Ok(sid) = Sid::from_bytes(&bytes) {
+            prop_assert_eq!(sid.to_string().parse::<Sid>(), Ok(sid));
+        }
+    }
+
+    #[test]
+    fn sid_round_trips(authority in 0..(1u64 << 48), subs in prop::collection::vec(any::<u32>(), 0..15)) {
+        let text = std::iter::once(format!("S-1-{}", authority))
+            .chain(subs.iter().map(u32::to_string))
+            .collect::<Vec<_>>()
+            .join("-");
+        let sid: Sid = text.parse().unwrap();
+        prop_assert_eq!(sid.to_string().parse::<Sid>(), Ok(sid));
+    }
+}
@@ -0,0 +1,185 @@
+//! Token buckets and summaries of the rate limit
+
+use std::time::Duration;
+
+mod common;
+
+use log::{Level, Log, Record};
+use win_service_logger::{Builder, Field, Logger, RateKey, RateLimit, RecordingSink};
+
+use common::{builder, log, messages};
+
+fn logger(limit: RateLimit) -> (Logger, RecordingSink) {
+    let sink = RecordingSink::new();
+    let logger = builder().rate_limit(limit).sink(sink.clone()).build();
+    (logger, sink)
+}
+
+#[test]
+fn call_sites_have_their_own_bucket() {
+    let limit = RateLimit::new(RateKey::CallSite).level(Level::Warn, 1, Duration::from_secs(60));
+    let (logger, sink) = logger(limit);
+    for i in 0..3 {
+        log(&logger, Level::Warn, 10, format!("retry {}", i));
+        log(&logger, Level::Warn, 20, format!("timeout {}", i));
+    }
+    assert_eq!(messages(&sink), ["retry 0", "timeout 0"]);
+    assert_eq!(logger.stats().suppressed, 4);
+}
+
+#[test]
+fn messages_have_their_own_bucket() {
+    let limit = RateLimit::new(RateKey::Message).level(Level::Error, 1, Duration::from_secs(60));
+    let (logger, sink) = logger(limit);
+    for line in 0..3 {
+        log(&logger, Level::Error, line, "disk full");
+        log(&logger, Level::Error, line, "out of memory");
+    }
+    assert_eq!(messages(&sink), ["disk full", "out of memory"]);
+}
+
+#[test]
+fn levels_without_a_limit_are_written() {
+    let limit = RateLimit::new(RateKey::CallSite).level(Level::Error, 1, Duration::from_secs(60));
+    let (logger, sink) = logger(limit);
+    for _ in 0..3 {
+        log(&logger, Level::Info, 10, "tick");
+    }
+    assert_eq!(messages(&sink).len(), 3);
+    assert_eq!(logger.stats().suppressed, 0);
+}
+
+#[test]
+fn summary_comes_before_the_next_written_record() {
+    let limit = RateLimit::new(RateKey::CallSite).level(Level::Error, 2, Duration::from_secs(2));
+    let (logger, sink) = logger(limit);
+    for _ in 0..4215 {
+        log(&logger, Level::Error, 10, "disk full");
+    }
+    // A token is back after a second
+    std::thread::sleep(Duration::from_millis(1100));
+    log(&logger, Level::Error, 10, "disk full");
+    assert_eq!(
+        messages(&sink),
+        [
+            "disk full",
+            "disk full",
+            "previous message repeated 4,213 times: disk full",
+            "disk full"
+        ]
+    );
+    assert_eq!(logger.stats().suppressed, 4213);
+}
+
+#[test]
+fn summaries_are_written_every_interval() {
+    let limit = RateLimit::new(RateKey::CallSite)
+        .level(Level::Error, 1, Duration::from_secs(3600))
+        .summary_interval(Duration::from_millis(100));
+    let (logger, sink) = logger(limit);
+    for _ in 0..3 {
+        log(&logger, Level::Error, 10, "disk full");
+    }
+    std::thread::sleep(Duration::from_millis(150));
+    log(&logger, Level::Error, 10, "disk full");
+    log(&logger, Level::Error, 10, "disk full");
+    assert_eq!(
+        messages(&sink),
+        ["disk full", "previous message repeated 3 times: disk full"]
+    );
+
+    // A record which stopped repeating is summarized on a later call from somewhere else
+    std::thread::sleep(Duration::from_millis(150));
+    log(&logger, Level::Error, 20, "unrelated");
+    assert_eq!(
+        messages(&sink)[2..],
+        ["previous message repeated 1 time: disk full", "unrelated"]
+    );
+}
+
+#[test]
+fn records_at_other_levels_write_due_summaries() {
+    let limit = RateLimit::new(RateKey::CallSite)
+        .level(Level::Error, 1, Duration::from_secs(3600))
+        .summary_interval(Duration::from_millis(100));
+    let (logger, sink) = logger(limit);
+    for _ in 0..3 {
+        log(&logger, Level::Error, 10, "disk full");
+    }
+    std::thread::sleep(Duration::from_millis(150));
+    log(&logger, Level::Info, 20, "tick");
+    assert_eq!(
+        messages(&sink),
+        [
+            "disk full",
+            "previous message repeated 2 times: disk full",
+            "tick"
+        ]
+    );
+}
+
+#[test]
+fn unique_messages_dont_stop_the_limit() {
+    let limit = RateLimit::new(RateKey::Message).level(Level::Error, 1, Duration::from_secs(3600));
+    let (logger, sink) = logger(limit);
+    for i in 0..10_000 {
+        log(&logger, Level::Error, 10, format!("request {} failed", i));
+    }
+    for _ in 0..3 {
+        log(&logger, Level::Error, 20, "disk full");
+    }
+    assert_eq!(messages(&sink).len(), 10_001);
+    assert_eq!(logger.stats().suppressed, 2);
+}
+
+#[test]
+fn shutdown_writes_pending_summaries() {
+    let limit = RateLimit::new(RateKey::CallSite).level(Level::Error, 1, Duration::from_secs(3600));
+    let (logger, sink) = logger(limit);
+    for _ in 0..3 {
+        log(&logger, Level::Error, 10, "disk full");
+    }
+    logger.shutdown();
+    assert_eq!(
+        messages(&sink),
+        ["disk full", "previous message repeated 2 times: disk full"]
+    );
+}
+
+#[test]
+fn summaries_use_the_format_and_fields() {
+    let sink = RecordingSink::new();
+    let logger = Builder::new()
+        .format(|buf, record| {
+            use std::fmt::Write;
+            write!(buf, "[{}] {}", record.level(), record.args())
+        })
+        .fields([Field::Message, Field::Key("disk".into())])
+        .rate_limit(RateLimit::new(RateKey::CallSite).level(
+            Level::Error,
+            1,
+            Duration::from_secs(3600),
+        ))
+        .sink(sink.clone())
+        .build();
+    let kvs = [("disk", "C:")];
+    for _ in 0..3 {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("disk full"))
+                .level(Level::Error)
+                .key_values(&kvs)
+                .build(),
+        );
+    }
+    logger.shutdown();
+
+    let strings: Vec<_> = sink.events().into_iter().map(|e| e.strings).collect();
+    assert_eq!(
+        strings,
+        [
+            ["[ERROR] disk full", "C:"],
+            ["[ERROR] previous message repeated 2 times: disk full", "C:"]
+        ]
+    );
+}
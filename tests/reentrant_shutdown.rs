@@ -0,0 +1,39 @@
+//! Shutting the global logger down from the error callback, while an event is being written
+
+use std::io;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use win_service_logger::{Builder, Event, EventSink, RecordingSink};
+
+struct Broken;
+
+impl EventSink for Broken {
+    fn report(&self, _: &Event) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(1502))
+    }
+}
+
+#[test]
+fn error_callback_can_shut_the_logger_down() {
+    let fallback = RecordingSink::new();
+    Builder::new()
+        .sink(Broken)
+        .fallback(fallback.clone())
+        .on_error(|_, _| win_service_logger::shutdown())
+        .init();
+
+    let (done, finished) = channel();
+    thread::spawn(move || {
+        log::error!("disk full");
+        log::error!("after shutdown");
+        let _ = done.send(());
+    });
+    finished
+        .recv_timeout(Duration::from_secs(10))
+        .expect("shutting down from the error callback deadlocked");
+
+    // The logger was closed before the event reached the fallback
+    assert!(fallback.events().is_empty());
+}